@@ -0,0 +1,638 @@
+//! A textual assembly format for `vm::Program`.
+//!
+//! Each line holds one instruction, with operands separated by spaces. A line may start with
+//! `label:`, and jump targets are either labels or absolute program counters. Columns are
+//! written as numbers or schema names, and `#` starts a comment. The colon-separated form of
+//! `query_iseq`, e.g. `Expect:0:12`, is still accepted.
+//!
+//! ```text
+//! .group 0 noun
+//! loop:
+//!     Split body, done
+//! body:
+//!     Save 0
+//!     ExpectIn pos {1,2}
+//!     Next
+//!     Save 1
+//!     Jump loop
+//! done:
+//!     Match
+//! ```
+//!
+//! Directives start with a dot: `.group SLOT [NAME]`, `.slots COUNT`, `.filter length MIN [MAX]`
+//! and `.filter contains` or `.filter not-contains`, which take a nested program up to `.end`.
+//! `Test` takes a predicate such as `(or (in pos {1,2}) (not (equal surface base)))`.
+
+use index::{FeatId, IndexData, COLS};
+use vm::{Filter, Group, InstCode, Look, Pred, Program};
+
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+#[derive(Debug)]
+pub struct AsmError {
+    /// 1-origin line number.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "assembly error at line {}: {}", self.line, self.message)
+    }
+}
+
+struct Line {
+    number: usize,
+    tokens: Vec<String>,
+}
+
+impl Line {
+    fn error<T, S: Into<String>>(&self, message: S) -> Result<T, AsmError> {
+        Err(AsmError {
+            line: self.number,
+            message: message.into(),
+        })
+    }
+
+    fn operand(&self, i: usize) -> Result<&str, AsmError> {
+        match self.tokens.get(i + 1) {
+            Some(token) => Ok(&token[..]),
+            None => self.error(format!("`{}` needs more operands", self.tokens[0])),
+        }
+    }
+
+    fn arity(&self, count: usize) -> Result<(), AsmError> {
+        if self.tokens.len() > count + 1 {
+            return self.error(format!("unexpected operand `{}`", self.tokens[count + 1]));
+        }
+        Ok(())
+    }
+
+    fn number(&self, i: usize) -> Result<usize, AsmError> {
+        let operand = self.operand(i)?;
+        match operand.parse::<usize>() {
+            Ok(number) => Ok(number),
+            Err(_) => self.error(format!("`{}` is not a number", operand)),
+        }
+    }
+
+    fn feature(&self, i: usize) -> Result<FeatId, AsmError> {
+        let operand = self.operand(i)?;
+        match operand.parse::<FeatId>() {
+            Ok(id) => Ok(id),
+            Err(_) => self.error(format!("`{}` is not a feature id", operand)),
+        }
+    }
+
+    /// `{1,2,3}`; the braces may be omitted.
+    fn set(&self, i: usize) -> Result<Vec<FeatId>, AsmError> {
+        let operand = self.operand(i)?;
+        let inner = operand.trim_start_matches('{').trim_end_matches('}');
+        let mut set = vec![];
+        for id in inner.split(',').filter(|id| !id.is_empty()) {
+            match id.parse::<FeatId>() {
+                Ok(id) => set.push(id),
+                Err(_) => return self.error(format!("`{}` is not a feature id", id)),
+            }
+        }
+        set.sort();
+        set.dedup();
+        Ok(set)
+    }
+
+    fn target(&self, i: usize, labels: &HashMap<String, usize>) -> Result<usize, AsmError> {
+        let operand = self.operand(i)?.trim_end_matches(',');
+        if let Ok(pc) = operand.parse::<usize>() {
+            return Ok(pc);
+        }
+        match labels.get(operand) {
+            Some(&pc) => Ok(pc),
+            None => self.error(format!("label `{}` is not defined", operand)),
+        }
+    }
+}
+
+fn lines(source: &str) -> Vec<Line> {
+    source.lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            }.trim();
+            // The colon-separated form has no spaces, while a label ends with its colon
+            let line = if !line.contains(char::is_whitespace) && line.contains(':') &&
+                          !line.ends_with(':') {
+                line.replace(':', " ")
+            } else {
+                line.to_string()
+            };
+            let tokens: Vec<String> = line.split_whitespace().map(|t| t.to_string()).collect();
+            if tokens.is_empty() {
+                None
+            } else {
+                Some(Line {
+                    number: i + 1,
+                    tokens: tokens,
+                })
+            }
+        })
+        .collect()
+}
+
+fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn look(line: &Line, i: usize) -> Result<Look, AsmError> {
+    match line.operand(i)? {
+        "Ahead" => Ok(Look::Ahead),
+        "NegativeAhead" => Ok(Look::NegativeAhead),
+        "Behind" => Ok(Look::Behind),
+        "NegativeBehind" => Ok(Look::NegativeBehind),
+        kind => line.error(format!("unknown look-around `{}`", kind)),
+    }
+}
+
+/// The slot an instruction reads or writes, if any.
+fn slot(inst: &InstCode) -> Option<usize> {
+    fn pred_slot(pred: &Pred) -> Option<usize> {
+        match *pred {
+            Pred::Same(_, slot) => Some(slot),
+            Pred::Not(ref pred) => pred_slot(pred),
+            Pred::And(ref preds) | Pred::Or(ref preds) => preds.iter().filter_map(pred_slot).max(),
+            Pred::In(..) | Pred::Equal(..) => None,
+        }
+    }
+    match *inst {
+        InstCode::ExpectSame(_, slot) |
+        InstCode::ExpectNotSame(_, slot) |
+        InstCode::Save(slot) |
+        InstCode::Progress(slot) |
+        InstCode::Intersect(slot, _) |
+        InstCode::Subtract(slot, _) => Some(slot),
+        InstCode::Test(ref pred) => pred_slot(pred),
+        _ => None,
+    }
+}
+
+struct Assembler<'a> {
+    schema: &'a [String],
+}
+
+impl<'a> Assembler<'a> {
+    fn column(&self, line: &Line, i: usize) -> Result<usize, AsmError> {
+        let operand = line.operand(i)?;
+        match operand.parse::<usize>() {
+            Ok(col) if col < COLS => Ok(col),
+            Ok(col) => line.error(format!("column {} does not exist", col)),
+            Err(_) => {
+                match self.schema.iter().position(|name| name == operand) {
+                    Some(col) => Ok(col),
+                    None => line.error(format!("unknown column `{}`", operand)),
+                }
+            }
+        }
+    }
+
+    /// Assembles lines up to the end of the input, or up to `.end` in a nested program.
+    fn program<I>(&self, lines: &mut I, nested: Option<usize>) -> Result<Program, AsmError>
+        where I: Iterator<Item = Line>
+    {
+        let mut labels = HashMap::new();
+        let mut inst_lines = vec![];
+        let mut groups = vec![];
+        let mut filters = vec![];
+        let mut slots = 0;
+        let mut closed = false;
+
+        while let Some(mut line) = lines.next() {
+            while line.tokens.first().map_or(false, |token| token.ends_with(':')) {
+                let token = line.tokens.remove(0);
+                let label = &token[..token.len() - 1];
+                if !is_label(label) {
+                    return line.error(format!("`{}` is not a valid label", label));
+                }
+                if labels.insert(label.to_string(), inst_lines.len()).is_some() {
+                    return line.error(format!("label `{}` is defined twice", label));
+                }
+            }
+            if line.tokens.is_empty() {
+                continue;
+            }
+            match &line.tokens[0][..] {
+                ".end" if nested.is_some() => {
+                    line.arity(0)?;
+                    closed = true;
+                    break;
+                }
+                ".group" => {
+                    let slot = line.number(0)?;
+                    let name = line.tokens.get(2).cloned();
+                    line.arity(2)?;
+                    slots = cmp::max(slots, slot + 2);
+                    groups.push(Group {
+                        name: name,
+                        slot: slot,
+                    });
+                }
+                ".slots" => {
+                    slots = cmp::max(slots, line.number(0)?);
+                    line.arity(1)?;
+                }
+                ".filter" => filters.push(self.filter(&line, lines)?),
+                directive if directive.starts_with('.') => {
+                    return line.error(format!("unknown directive `{}`", directive));
+                }
+                _ => inst_lines.push(line),
+            }
+        }
+        if let Some(begin) = nested {
+            if !closed {
+                return Err(AsmError {
+                    line: begin,
+                    message: "`.filter` is not closed by `.end`".to_string(),
+                });
+            }
+        }
+
+        let mut inst_seq = Vec::with_capacity(inst_lines.len());
+        for line in &inst_lines {
+            let inst = self.inst(line, &labels)?;
+            if let Some(slot) = slot(&inst) {
+                slots = cmp::max(slots, slot + 1);
+            }
+            inst_seq.push(inst);
+        }
+
+        Ok(Program {
+            inst_seq: inst_seq,
+            groups: groups,
+            slots: slots,
+            filters: filters,
+        })
+    }
+
+    fn filter<I>(&self, line: &Line, lines: &mut I) -> Result<Filter, AsmError>
+        where I: Iterator<Item = Line>
+    {
+        match line.operand(0)? {
+            "length" => {
+                let min = line.number(1)?;
+                let max = if line.tokens.len() > 3 {
+                    Some(line.number(2)?)
+                } else {
+                    None
+                };
+                line.arity(3)?;
+                Ok(Filter::Length(min, max))
+            }
+            "contains" => {
+                line.arity(1)?;
+                Ok(Filter::Contains(self.program(lines, Some(line.number))?))
+            }
+            "not-contains" => {
+                line.arity(1)?;
+                Ok(Filter::NotContains(self.program(lines, Some(line.number))?))
+            }
+            kind => line.error(format!("unknown filter `{}`", kind)),
+        }
+    }
+
+    fn inst(&self, line: &Line, labels: &HashMap<String, usize>) -> Result<InstCode, AsmError> {
+        let (inst, arity) = match &line.tokens[0][..] {
+            "Match" => (InstCode::Match, 0),
+            "Next" => (InstCode::Next, 0),
+            "Noop" => (InstCode::Noop, 0),
+            "AssertStart" => (InstCode::AssertStart, 0),
+            "AssertEnd" => (InstCode::AssertEnd, 0),
+            "Jump" => (InstCode::Jump(line.target(0, labels)?), 1),
+            "Split" => (InstCode::Split(line.target(0, labels)?, line.target(1, labels)?), 2),
+            "Expect" => (InstCode::Expect(self.column(line, 0)?, line.feature(1)?), 2),
+            "ExpectNot" => (InstCode::ExpectNot(self.column(line, 0)?, line.feature(1)?), 2),
+            "ExpectIn" => (InstCode::ExpectIn(self.column(line, 0)?, line.set(1)?), 2),
+            "ExpectNotIn" => (InstCode::ExpectNotIn(self.column(line, 0)?, line.set(1)?), 2),
+            "ExpectSame" => (InstCode::ExpectSame(self.column(line, 0)?, line.number(1)?), 2),
+            "ExpectNotSame" => {
+                (InstCode::ExpectNotSame(self.column(line, 0)?, line.number(1)?), 2)
+            }
+            "Save" => (InstCode::Save(line.number(0)?), 1),
+            "Progress" => (InstCode::Progress(line.number(0)?), 1),
+            "Look" => (InstCode::Look(look(line, 0)?, line.target(1, labels)?), 2),
+            "Intersect" => (InstCode::Intersect(line.number(0)?, line.target(1, labels)?), 2),
+            "Subtract" => (InstCode::Subtract(line.number(0)?, line.target(1, labels)?), 2),
+            "Test" => {
+                let text = line.tokens[1..].join(" ").replace('(', " ( ").replace(')', " ) ");
+                let tokens: Vec<&str> = text.split_whitespace().collect();
+                let mut tokens = tokens.into_iter().peekable();
+                let pred = self.pred(line, &mut tokens)?;
+                if let Some(token) = tokens.next() {
+                    return line.error(format!("unexpected `{}` after the predicate", token));
+                }
+                return Ok(InstCode::Test(pred));
+            }
+            opcode => return line.error(format!("unknown instruction `{}`", opcode)),
+        };
+        line.arity(arity)?;
+        Ok(inst)
+    }
+
+    fn pred<'t, I>(&self,
+                   line: &Line,
+                   tokens: &mut ::std::iter::Peekable<I>)
+                   -> Result<Pred, AsmError>
+        where I: Iterator<Item = &'t str>
+    {
+        if tokens.next() != Some("(") {
+            return line.error("a predicate has to start with `(`");
+        }
+        let operator = match tokens.next() {
+            Some(operator) => operator,
+            None => return line.error("the predicate is not closed"),
+        };
+        let mut operands = vec![];
+        let mut preds = vec![];
+        loop {
+            match tokens.peek().cloned() {
+                None => return line.error("the predicate is not closed"),
+                Some(")") => {
+                    tokens.next();
+                    break;
+                }
+                Some("(") => preds.push(self.pred(line, tokens)?),
+                Some(operand) => {
+                    tokens.next();
+                    operands.push(operand.to_string());
+                }
+            }
+        }
+
+        // Operands are checked with the same helpers as instructions
+        let mut args = vec![operator.to_string()];
+        args.extend(operands);
+        let args = Line {
+            number: line.number,
+            tokens: args,
+        };
+        let pred = match operator {
+            "in" => Pred::In(self.column(&args, 0)?, args.set(1)?),
+            "same" => Pred::Same(self.column(&args, 0)?, args.number(1)?),
+            "equal" => Pred::Equal(self.column(&args, 0)?, self.column(&args, 1)?),
+            "not" if preds.len() == 1 => Pred::Not(Box::new(preds.pop().unwrap())),
+            "not" => return line.error("`not` takes exactly one predicate"),
+            "and" => Pred::And(preds),
+            "or" => Pred::Or(preds),
+            operator => return line.error(format!("unknown predicate `{}`", operator)),
+        };
+        let arity = match pred {
+            Pred::In(..) | Pred::Same(..) | Pred::Equal(..) => 2,
+            _ => 0,
+        };
+        args.arity(arity)?;
+        Ok(pred)
+    }
+}
+
+pub fn assemble(source: &str, schema: &[String]) -> Result<Program, AsmError> {
+    let assembler = Assembler { schema: schema };
+    assembler.program(&mut lines(source).into_iter(), None)
+}
+
+struct Disassembler<'a> {
+    index_data: &'a IndexData<'a>,
+}
+
+impl<'a> Disassembler<'a> {
+    fn column(&self, col: usize) -> String {
+        match self.index_data.schema.get(col) {
+            Some(name) => name.clone(),
+            None => col.to_string(),
+        }
+    }
+
+    /// Shows the strings of the features an instruction refers to.
+    fn comment(&self, col: usize, ids: &[FeatId]) -> String {
+        const SHOWN: usize = 5;
+        let features = &self.index_data.features_per_column[col];
+        let mut strings: Vec<String> = ids.iter()
+            .take(SHOWN)
+            .map(|&id| match features.get(id as usize) {
+                Some(feat) => String::from_utf8_lossy(feat).into_owned(),
+                None => "?".to_string(),
+            })
+            .collect();
+        if ids.len() > SHOWN {
+            strings.push(format!("... ({} in total)", ids.len()));
+        }
+        format!("  # {}", strings.join("|"))
+    }
+
+    fn set(ids: &[FeatId]) -> String {
+        let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        format!("{{{}}}", ids.join(","))
+    }
+
+    fn pred(&self, pred: &Pred) -> String {
+        match *pred {
+            Pred::In(col, ref ids) => {
+                format!("(in {} {})", self.column(col), Disassembler::set(ids))
+            }
+            Pred::Same(col, slot) => format!("(same {} {})", self.column(col), slot),
+            Pred::Equal(left, right) => {
+                format!("(equal {} {})", self.column(left), self.column(right))
+            }
+            Pred::Not(ref pred) => format!("(not {})", self.pred(pred)),
+            Pred::And(ref preds) | Pred::Or(ref preds) => {
+                let operator = match *pred {
+                    Pred::And(_) => "and",
+                    _ => "or",
+                };
+                let preds: Vec<String> = preds.iter().map(|pred| self.pred(pred)).collect();
+                if preds.is_empty() {
+                    format!("({})", operator)
+                } else {
+                    format!("({} {})", operator, preds.join(" "))
+                }
+            }
+        }
+    }
+
+    fn inst(&self, inst: &InstCode) -> String {
+        let label = |pc: usize| format!("L{}", pc);
+        match *inst {
+            InstCode::Expect(col, id) => {
+                format!("Expect {} {}{}", self.column(col), id, self.comment(col, &[id]))
+            }
+            InstCode::ExpectNot(col, id) => {
+                format!("ExpectNot {} {}{}", self.column(col), id, self.comment(col, &[id]))
+            }
+            InstCode::ExpectIn(col, ref ids) => {
+                format!("ExpectIn {} {}{}",
+                        self.column(col),
+                        Disassembler::set(ids),
+                        self.comment(col, ids))
+            }
+            InstCode::ExpectNotIn(col, ref ids) => {
+                format!("ExpectNotIn {} {}{}",
+                        self.column(col),
+                        Disassembler::set(ids),
+                        self.comment(col, ids))
+            }
+            InstCode::ExpectSame(col, slot) => format!("ExpectSame {} {}", self.column(col), slot),
+            InstCode::ExpectNotSame(col, slot) => {
+                format!("ExpectNotSame {} {}", self.column(col), slot)
+            }
+            InstCode::Match => "Match".to_string(),
+            InstCode::Jump(pc) => format!("Jump {}", label(pc)),
+            InstCode::Next => "Next".to_string(),
+            InstCode::Split(x, y) => format!("Split {}, {}", label(x), label(y)),
+            InstCode::Noop => "Noop".to_string(),
+            InstCode::AssertStart => "AssertStart".to_string(),
+            InstCode::AssertEnd => "AssertEnd".to_string(),
+            InstCode::Save(slot) => format!("Save {}", slot),
+            InstCode::Progress(slot) => format!("Progress {}", slot),
+            InstCode::Look(look, pc) => format!("Look {:?} {}", look, label(pc)),
+            InstCode::Intersect(slot, pc) => format!("Intersect {} {}", slot, label(pc)),
+            InstCode::Subtract(slot, pc) => format!("Subtract {} {}", slot, label(pc)),
+            InstCode::Test(ref pred) => format!("Test {}", self.pred(pred)),
+        }
+    }
+
+    fn program(&self, out: &mut String, program: &Program, depth: usize) {
+        let indent = "    ".repeat(depth);
+        for group in &program.groups {
+            out.push_str(&format!("{}.group {}", indent, group.slot));
+            if let Some(ref name) = group.name {
+                out.push_str(&format!(" {}", name));
+            }
+            out.push_str("\n");
+        }
+        for filter in &program.filters {
+            match *filter {
+                Filter::Contains(ref program) | Filter::NotContains(ref program) => {
+                    let kind = match *filter {
+                        Filter::Contains(_) => "contains",
+                        _ => "not-contains",
+                    };
+                    out.push_str(&format!("{}.filter {}\n", indent, kind));
+                    self.program(out, program, depth + 1);
+                    out.push_str(&format!("{}.end\n", indent));
+                }
+                Filter::Length(min, Some(max)) => {
+                    out.push_str(&format!("{}.filter length {} {}\n", indent, min, max));
+                }
+                Filter::Length(min, None) => {
+                    out.push_str(&format!("{}.filter length {}\n", indent, min));
+                }
+            }
+        }
+
+        let mut targets = BTreeSet::new();
+        for inst in &program.inst_seq {
+            match *inst {
+                InstCode::Jump(pc) |
+                InstCode::Look(_, pc) |
+                InstCode::Intersect(_, pc) |
+                InstCode::Subtract(_, pc) => {
+                    targets.insert(pc);
+                }
+                InstCode::Split(x, y) => {
+                    targets.insert(x);
+                    targets.insert(y);
+                }
+                _ => {}
+            }
+        }
+        for (pc, inst) in program.inst_seq.iter().enumerate() {
+            if targets.contains(&pc) {
+                out.push_str(&format!("{}L{}:\n", indent, pc));
+            }
+            out.push_str(&format!("{}    {}\n", indent, self.inst(inst)));
+        }
+        if targets.contains(&program.inst_seq.len()) {
+            out.push_str(&format!("{}L{}:\n", indent, program.inst_seq.len()));
+        }
+    }
+}
+
+/// Prints a program in the format `assemble` reads, with feature strings in comments.
+pub fn disassemble(program: &Program, index_data: &IndexData) -> String {
+    let mut out = String::new();
+    Disassembler { index_data: index_data }.program(&mut out, program, 0);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm::testing::{compile_query, index_data};
+
+    fn assert_round_trip(program: &Program) {
+        let source = disassemble(program, &index_data(&[]));
+        match assemble(&source, &[]) {
+            Ok(assembled) => assert_eq!(&assembled, program, "disassembled as\n{}", source),
+            Err(err) => panic!("disassembled as\n{}which fails to assemble: {}", source, err),
+        }
+    }
+
+    fn assert_error(source: &str, line: usize, message: &str) {
+        match assemble(source, &[]) {
+            Ok(program) => panic!("{:?} is assembled as {:?}", source, program),
+            Err(err) => {
+                assert_eq!(err.line, line, "{}", err);
+                assert!(err.message.contains(message), "{}", err);
+            }
+        }
+    }
+
+    #[test]
+    fn disassembled_programs_assemble_back() {
+        for q in &["1 (2 | 3)* (?<x>4+) (?:5 6){1,3} 7? 8{2,}",
+                   "^ 1 | 2 $",
+                   "(?=1) 2 (?!3) (?<=1 2) 3 (?<!4)",
+                   "(?:1?)*",
+                   "1 [] 2 & [] 3 []",
+                   "1 []* - 1 2"] {
+            assert_round_trip(&compile_query(q));
+        }
+        let source = ".group 0 x\n\
+                      .filter length 2 10\n\
+                      .filter not-contains\n\
+                          Expect 0 3\n\
+                          Match\n\
+                      .end\n\
+                      Save 0\n\
+                      Test (or (in 0 {1,2}) (not (equal 0 1)) (and (same 2 0)))\n\
+                      ExpectNotIn 1 {4,5}\n\
+                      Next\n\
+                      Save 1\n\
+                      Match";
+        assert_round_trip(&assemble(source, &[]).unwrap());
+    }
+
+    #[test]
+    fn colon_separated_form() {
+        let program = assemble("Expect:0:12\nNext\nSplit:0:3\nMatch", &[]).unwrap();
+        assert_eq!(program.inst_seq,
+                   vec![InstCode::Expect(0, 12),
+                        InstCode::Next,
+                        InstCode::Split(0, 3),
+                        InstCode::Match]);
+    }
+
+    #[test]
+    fn errors_point_at_the_line() {
+        assert_error("Next\nJump nowhere\nMatch", 2, "label `nowhere` is not defined");
+        assert_error("a:\nNext\na: Match", 3, "label `a` is defined twice");
+        assert_error("Next\n.filter contains\nExpect 0 1\nMatch",
+                     2,
+                     "`.filter` is not closed by `.end`");
+    }
+}
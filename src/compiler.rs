@@ -1,187 +1,2244 @@
-use index::{FeatId};
-use vm::InstCode;
-use std::collections::LinkedList;
+use index::{FeatId, FeatList};
+use regex::bytes::Regex;
+use vm::{self, Group, InstCode, Look, Pred, Program};
+use std::collections::{HashMap, LinkedList};
+use std::fmt;
+use std::mem;
+use std::iter;
 use combine::*;
 use combine::char::*;
 use combine::combinator::*;
+use combine::primitives::{Error, Info};
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Feature {
+    Any,
+    Id(FeatId),
+    Literal(String),
+    Regex(String),
+    /// `$x`: the value of the same column at the token bound to `x`.
+    Var(String),
+    Not(Box<Feature>),
+    Set(Vec<Feature>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Column {
+    Index(usize),
+    Name(String),
+}
+
+/// A boolean expression over the columns of a single token.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Predicate {
+    Is(Column, Feature),
+    /// `[a==b]`: the two columns have the same string, e.g. an uninflected word.
+    Equal(Column, Column),
+    And(Vec<Predicate>),
+    Or(Vec<Predicate>),
+    Not(Box<Predicate>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Node {
-    Pattern(Vec<Option<FeatId>>),
-    Union(Box<Node>, Box<Node>),
+    Pattern(Vec<(Column, Feature)>),
+    /// A token written with `|`, `!` or parentheses, e.g. `[pos="a" | pos="b" & base="c"]`.
+    /// Plain conjunctions are parsed into `Pattern` instead.
+    Test(Predicate),
+    Union(Vec<Node>),
     Concat(Vec<Node>),
     Star(Box<Node>),
+    Plus(Box<Node>),
+    Optional(Box<Node>),
+    Repeat(Box<Node>, u32, Option<u32>),
+    /// `^`: the beginning of a sentence.
+    Start,
+    /// `$`: the end of a sentence, after its terminating token.
+    End,
+    /// `( ... )` or `(?<name> ... )`.
+    Group(Option<String>, Box<Node>),
+    /// `$x: ...` binds `x` to the first token of the node.
+    Bind(String, Box<Node>),
+    /// `(?= ...)`, `(?! ...)`, `(?<= ...)` or `(?<! ...)`.
+    Look(Look, Box<Node>),
+    /// `NEAR/k(x, y)`: `x` and `y` in either order with at most `k` tokens in between.
+    Near(u32, Box<Node>, Box<Node>),
+    /// `x & y`: a span that both `x` and `y` match.
+    Intersect(Box<Node>, Box<Node>),
+    /// `x - y`: a span that `x` matches but `y` does not.
+    Difference(Box<Node>, Box<Node>),
+    /// A reference to a pattern defined by `let`, replaced by `expand`.
+    Ref(String),
+    /// `... within sentence where ...`: only searches the sentences that pass every filter.
+    /// Appears only at the top of a query.
+    Within(Box<Node>, Vec<Filter>),
     Empty,
 }
 
-type FnPtrParser<O, I> = FnParser<I, fn(I) -> ParseResult<O, I>>;
-type QueryParser<O, I> = Expected<FnPtrParser<O, I>>;
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    /// `contains (...)`
+    Contains(Node),
+    /// `not contains (...)`
+    NotContains(Node),
+    /// `length {m,n}`: the number of tokens in the sentence.
+    Length(u32, Option<u32>),
+}
+
+/// `let NAME = ...;` definitions followed by the pattern to search for.
+#[derive(Debug)]
+pub struct Query {
+    pub definitions: Vec<(String, Node)>,
+    pub pattern: Node,
+}
+
+enum Operand {
+    Is(Feature),
+    Equal(Column),
+    NotEqual(Column),
+}
+
+enum GroupKind {
+    Capture(Option<String>),
+    NonCapture,
+    Look(Look),
+}
 
-struct Query<I>(::std::marker::PhantomData<fn(I) -> I>);
+pub type FnPtrParser<O, I> = FnParser<I, fn(I) -> ParseResult<O, I>>;
+pub type QueryParser<O, I> = Expected<FnPtrParser<O, I>>;
 
-fn fn_parser<O, I>(f: fn(I) -> ParseResult<O, I>, err: &'static str) -> QueryParser<O, I>
+struct Grammar<I>(::std::marker::PhantomData<fn(I) -> I>);
+
+pub fn fn_parser<O, I>(f: fn(I) -> ParseResult<O, I>, err: &'static str) -> QueryParser<O, I>
     where I: Stream<Item = char>
 {
     parser(f).expected(err)
 }
 
-impl<I> Query<I>
+/// Builds the node for the contents of `[...]`. Plain conjunctions become a `Pattern`.
+pub fn token_node(predicate: Option<Predicate>) -> Node {
+    match predicate {
+        None => Node::Pattern(vec![]),
+        Some(Predicate::Is(column, feature)) => Node::Pattern(vec![(column, feature)]),
+        Some(Predicate::And(predicates)) => {
+            if predicates.iter().all(|predicate| match *predicate {
+                Predicate::Is(..) => true,
+                _ => false,
+            }) {
+                Node::Pattern(predicates.into_iter()
+                    .map(|predicate| match predicate {
+                        Predicate::Is(column, feature) => (column, feature),
+                        _ => unreachable!(),
+                    })
+                    .collect())
+            } else {
+                Node::Test(Predicate::And(predicates))
+            }
+        }
+        Some(predicate) => Node::Test(predicate),
+    }
+}
+
+impl<I> Grammar<I>
     where I: Stream<Item = char>
 {
+    /// Whitespace, including newlines, and `#` comments that run to the end of the line.
+    fn blank() -> FnPtrParser<(), I> {
+        parser(Grammar::<I>::blank_ as fn(_) -> _)
+    }
+    fn blank_(input: I) -> ParseResult<(), I> {
+        let comment = (char('#'), skip_many(none_of("\n".chars()))).map(|_| ());
+        skip_many(space().map(|_| ()).or(comment)).parse_lazy(input).into()
+    }
+
     fn integer() -> QueryParser<u32, I> {
-        fn_parser(Query::<I>::integer_, "integer")
+        fn_parser(Grammar::<I>::integer_, "integer")
     }
     fn integer_(input: I) -> ParseResult<u32, I> {
-        many1::<String, _>(digit()).map(|ds| ds.parse::<u32>().unwrap()).parse_lazy(input).into()
+        many1::<String, _>(digit())
+            .and_then(|ds| -> Result<u32, Error<char, I::Range>> {
+                ds.parse::<u32>().map_err(|_| Error::Message(Info::Borrowed("number is too large")))
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn literal() -> QueryParser<String, I> {
+        fn_parser(Grammar::<I>::literal_, "string")
+    }
+    fn literal_(input: I) -> ParseResult<String, I> {
+        let escaped = char('\\').with(any());
+        let chars = many::<String, _>(escaped.or(none_of("\"\\".chars())));
+        between(char('"'), char('"'), chars).parse_lazy(input).into()
     }
 
-    fn feature() -> QueryParser<Option<u32>, I> {
-        fn_parser(Query::<I>::feature_, "feature")
+    fn feature() -> QueryParser<Feature, I> {
+        fn_parser(Grammar::<I>::feature_, "feature")
     }
-    fn feature_(input: I) -> ParseResult<Option<u32>, I> {
-        let int = Query::<I>::integer().map(|a| Some(a));
-        let any = char('/').map(|_| None);
-        int.or(any)
+    fn feature_(input: I) -> ParseResult<Feature, I> {
+        let any = char('/').or(char('_')).map(|_| Feature::Any);
+        let not = char('!')
+            .with(Grammar::<I>::feature())
+            .map(|feature| Feature::Not(Box::new(feature)));
+        let var = try(char('$').with(Grammar::<I>::identifier())).map(Feature::Var);
+        let set_open = char('{').skip(Grammar::<I>::blank());
+        let set_close = char('}');
+        let pipe = char('|').skip(Grammar::<I>::blank());
+        let set = between(set_open,
+                          set_close,
+                          sep_by1::<Vec<_>, _, _>(Grammar::<I>::atom().skip(Grammar::<I>::blank()), pipe))
+            .map(Feature::Set);
+        Grammar::<I>::atom().or(any).or(not).or(var).or(set)
             .parse_lazy(input).into()
     }
 
+    fn atom() -> QueryParser<Feature, I> {
+        fn_parser(Grammar::<I>::atom_, "feature")
+    }
+    fn atom_(input: I) -> ParseResult<Feature, I> {
+        let int = Grammar::<I>::integer().map(Feature::Id);
+        let lit = Grammar::<I>::literal().map(Feature::Literal);
+        let regex = Grammar::<I>::regex().map(Feature::Regex);
+        int.or(lit).or(try(regex))
+            .parse_lazy(input).into()
+    }
+
+    /// `/^食べ/`, `/的$/` or `/[ァ-ヶ]+/`, which also covers prefix and suffix searches.
+    /// A `/` directly followed by something that can come after a wildcard, such as a space,
+    /// `-`, `]` or a quantifier, is the wildcard itself. Otherwise the closing `/` decides, and
+    /// it has to come before the next blank so that `/(1) /` stays two wildcards and a group.
+    fn regex() -> QueryParser<String, I> {
+        fn_parser(Grammar::<I>::regex_, "regex")
+    }
+    fn regex_(input: I) -> ParseResult<String, I> {
+        let head = satisfy(|c: char| !c.is_whitespace() && !"-/])}|&,;!$#*+?{".contains(c));
+        let escaped = (char('\\'), any()).map(|(_, c): (char, char)| if c == '/' {
+            c.to_string()
+        } else {
+            format!("\\{}", c)
+        });
+        let plain = satisfy(|c: char| !c.is_whitespace() && c != '/' && c != '\\');
+        let chunk = escaped.or(plain.map(|c: char| c.to_string()));
+        let body = (look_ahead(head), many::<Vec<String>, _>(chunk))
+            .map(|(_, chunks)| chunks.concat());
+        between(char('/'), char('/'), body).parse_lazy(input).into()
+    }
+
     fn morpheme() -> QueryParser<Node, I> {
-        fn_parser(Query::<I>::morpheme_, "pattern")
+        fn_parser(Grammar::<I>::morpheme_, "pattern")
     }
     fn morpheme_(input: I) -> ParseResult<Node, I> {
-        sep_by1(Query::<I>::feature(), char('-')).skip(spaces())
-            .map(|features| Node::Pattern(features))
+        sep_by1::<Vec<_>, _, _>(Grammar::<I>::feature(), char('-')).skip(Grammar::<I>::blank())
+            .map(|features| {
+                let slots = features.into_iter()
+                    .enumerate()
+                    .map(|(col, feature)| (Column::Index(col), feature))
+                    .collect();
+                Node::Pattern(slots)
+            })
             .parse_lazy(input).into()
     }
 
+    fn column() -> QueryParser<Column, I> {
+        fn_parser(Grammar::<I>::column_, "column")
+    }
+    fn column_(input: I) -> ParseResult<Column, I> {
+        let index = Grammar::<I>::integer().map(|col| Column::Index(col as usize));
+        let name = Grammar::<I>::identifier().map(Column::Name);
+        index.or(name).parse_lazy(input).into()
+    }
+
+    /// Pattern names start with an uppercase letter so that they never clash with keywords.
+    fn pattern_name() -> QueryParser<String, I> {
+        fn_parser(Grammar::<I>::pattern_name_, "pattern name")
+    }
+    fn pattern_name_(input: I) -> ParseResult<String, I> {
+        (upper(), many::<String, _>(alpha_num().or(char('_'))))
+            .map(|(head, tail)| format!("{}{}", head, tail))
+            .parse_lazy(input).into()
+    }
+
+    fn identifier() -> QueryParser<String, I> {
+        fn_parser(Grammar::<I>::identifier_, "identifier")
+    }
+    fn identifier_(input: I) -> ParseResult<String, I> {
+        (letter(), many::<String, _>(alpha_num().or(char('_'))))
+            .map(|(head, tail)| format!("{}{}", head, tail))
+            .parse_lazy(input).into()
+    }
+
+    /// `col = feature`, `col == col` or `col != col`.
+    fn constraint() -> QueryParser<Predicate, I> {
+        fn_parser(Grammar::<I>::constraint_, "constraint")
+    }
+    fn constraint_(input: I) -> ParseResult<Predicate, I> {
+        let column = || Grammar::<I>::column().skip(Grammar::<I>::blank());
+        let equal = try(string("==")).skip(Grammar::<I>::blank()).with(column());
+        let not_equal = string("!=").skip(Grammar::<I>::blank()).with(column());
+        let is = char('=').skip(Grammar::<I>::blank()).with(Grammar::<I>::feature());
+        let operand = equal.map(Operand::Equal)
+            .or(not_equal.map(Operand::NotEqual))
+            .or(is.skip(Grammar::<I>::blank()).map(Operand::Is));
+        (column(), operand)
+            .map(|(left, operand)| match operand {
+                Operand::Is(feature) => Predicate::Is(left, feature),
+                Operand::Equal(right) => Predicate::Equal(left, right),
+                Operand::NotEqual(right) => {
+                    Predicate::Not(Box::new(Predicate::Equal(left, right)))
+                }
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn token() -> QueryParser<Node, I> {
+        fn_parser(Grammar::<I>::token_, "token")
+    }
+    fn token_(input: I) -> ParseResult<Node, I> {
+        let bracket_open = char('[').skip(Grammar::<I>::blank());
+        let bracket_close = char(']').skip(Grammar::<I>::blank());
+        between(bracket_open, bracket_close, optional(Grammar::<I>::predicate()))
+            .map(token_node)
+            .parse_lazy(input).into()
+    }
+
+    /// `|` binds looser than `&`, which may be omitted between two operands.
+    fn predicate() -> QueryParser<Predicate, I> {
+        fn_parser(Grammar::<I>::predicate_, "constraint")
+    }
+    fn predicate_(input: I) -> ParseResult<Predicate, I> {
+        let pipe = char('|').skip(Grammar::<I>::blank());
+        sep_by1::<Vec<_>, _, _>(Grammar::<I>::conjunction(), pipe)
+            .map(|mut predicates| if predicates.len() == 1 {
+                predicates.pop().unwrap()
+            } else {
+                Predicate::Or(predicates)
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn conjunction() -> QueryParser<Predicate, I> {
+        fn_parser(Grammar::<I>::conjunction_, "constraint")
+    }
+    fn conjunction_(input: I) -> ParseResult<Predicate, I> {
+        let ampersand = optional(char('&').skip(Grammar::<I>::blank()));
+        let rest = many::<Vec<_>, _>(ampersand.with(Grammar::<I>::negation()));
+        (Grammar::<I>::negation(), rest)
+            .map(|(first, mut rest)| if rest.is_empty() {
+                first
+            } else {
+                rest.insert(0, first);
+                Predicate::And(rest)
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn negation() -> QueryParser<Predicate, I> {
+        fn_parser(Grammar::<I>::negation_, "constraint")
+    }
+    fn negation_(input: I) -> ParseResult<Predicate, I> {
+        let not = char('!')
+            .skip(Grammar::<I>::blank())
+            .with(Grammar::<I>::negation())
+            .map(|predicate| Predicate::Not(Box::new(predicate)));
+        let paren_open = char('(').skip(Grammar::<I>::blank());
+        let paren_close = char(')').skip(Grammar::<I>::blank());
+        let paren = between(paren_open, paren_close, Grammar::<I>::predicate());
+        not.or(paren).or(Grammar::<I>::constraint()).parse_lazy(input).into()
+    }
+
     fn factor() -> QueryParser<Node, I> {
-        fn_parser(Query::<I>::factor_, "bare")
+        fn_parser(Grammar::<I>::factor_, "pattern")
     }
+    fn group_kind() -> QueryParser<GroupKind, I> {
+        fn_parser(Grammar::<I>::group_kind_, "group")
+    }
+    fn group_kind_(input: I) -> ParseResult<GroupKind, I> {
+        let non_capture = char(':').map(|_| GroupKind::NonCapture);
+        let ahead = char('=').map(|_| GroupKind::Look(Look::Ahead));
+        let negative_ahead = char('!').map(|_| GroupKind::Look(Look::NegativeAhead));
+        let behind = char('=').map(|_| GroupKind::Look(Look::Behind));
+        let negative_behind = char('!').map(|_| GroupKind::Look(Look::NegativeBehind));
+        let named = Grammar::<I>::identifier()
+            .skip(char('>'))
+            .map(|name| GroupKind::Capture(Some(name)));
+        let angle = char('<').with(behind.or(negative_behind).or(named));
+        let extension = char('?').with(non_capture.or(ahead).or(negative_ahead).or(angle));
+        optional(extension)
+            .map(|kind| kind.unwrap_or(GroupKind::Capture(None)))
+            .skip(Grammar::<I>::blank())
+            .parse_lazy(input).into()
+    }
+
     fn factor_(input: I) -> ParseResult<Node, I> {
-        let paren_open = char('(').skip(spaces());
-        let paren_close = char(')').skip(spaces());
-        let group = between(paren_open, paren_close, Query::<I>::subexpr());
-        Query::<I>::morpheme().or(group).parse_lazy(input).into()
+        let paren_open = char('(');
+        let paren_close = char(')').skip(Grammar::<I>::blank());
+        let group = between(paren_open,
+                            paren_close,
+                            (Grammar::<I>::group_kind(), Grammar::<I>::subexpr()))
+            .map(|(kind, node)| match kind {
+                GroupKind::Capture(name) => Node::Group(name, Box::new(node)),
+                GroupKind::NonCapture => node,
+                GroupKind::Look(look) => Node::Look(look, Box::new(node)),
+            });
+        let start = char('^').skip(Grammar::<I>::blank()).map(|_| Node::Start);
+        let end = char('$').skip(Grammar::<I>::blank()).map(|_| Node::End);
+        let binder = try((char('$'), Grammar::<I>::identifier(), char(':'))).skip(Grammar::<I>::blank());
+        let bound = (binder, Grammar::<I>::factor())
+            .map(|((_, name, _), node)| Node::Bind(name, Box::new(node)));
+        let reference = Grammar::<I>::pattern_name().skip(Grammar::<I>::blank()).map(Node::Ref);
+        bound.or(Grammar::<I>::morpheme())
+            .or(Grammar::<I>::token())
+            .or(group)
+            .or(start)
+            .or(end)
+            .or(Grammar::<I>::near())
+            .or(reference)
+            .parse_lazy(input).into()
+    }
+
+    fn near() -> QueryParser<Node, I> {
+        fn_parser(Grammar::<I>::near_, "pattern")
+    }
+    fn near_(input: I) -> ParseResult<Node, I> {
+        let keyword = try(string("NEAR/"));
+        let paren_open = char('(').skip(Grammar::<I>::blank());
+        let comma = char(',').skip(Grammar::<I>::blank());
+        let paren_close = char(')').skip(Grammar::<I>::blank());
+        let operands = (Grammar::<I>::subexpr(), comma, Grammar::<I>::subexpr());
+        (keyword, Grammar::<I>::integer(), between(paren_open, paren_close, operands))
+            .map(|(_, distance, (left, _, right))| {
+                Node::Near(distance, Box::new(left), Box::new(right))
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn bounds() -> QueryParser<(u32, Option<u32>), I> {
+        fn_parser(Grammar::<I>::bounds_, "repetition")
+    }
+    fn bounds_(input: I) -> ParseResult<(u32, Option<u32>), I> {
+        let upper = optional(char(',').with(optional(Grammar::<I>::integer())));
+        between(char('{'), char('}'), (Grammar::<I>::integer(), upper))
+            .and_then(|(min, upper)| -> Result<(u32, Option<u32>), Error<char, I::Range>> {
+                let max = match upper {
+                    None => Some(min),
+                    Some(max) => max,
+                };
+                match max {
+                    Some(max) if max < min => {
+                        Err(Error::Message(Info::Borrowed("upper bound is less than lower bound")))
+                    }
+                    _ => Ok((min, max)),
+                }
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn quantifier() -> QueryParser<(u32, Option<u32>), I> {
+        fn_parser(Grammar::<I>::quantifier_, "quantifier")
+    }
+    fn quantifier_(input: I) -> ParseResult<(u32, Option<u32>), I> {
+        let star = char('*').map(|_| (0, None));
+        let plus = char('+').map(|_| (1, None));
+        let question = char('?').map(|_| (0, Some(1)));
+        // Once `{` and a digit are seen, errors in the bounds are reported as they are
+        let bounds = try(look_ahead((char('{'), digit()))).with(Grammar::<I>::bounds());
+        star.or(plus).or(question).or(bounds).skip(Grammar::<I>::blank())
+            .parse_lazy(input).into()
     }
 
     fn star() -> QueryParser<Node, I> {
-        fn_parser(Query::<I>::star_, "star")
+        fn_parser(Grammar::<I>::star_, "pattern")
     }
     fn star_(input: I) -> ParseResult<Node, I> {
-        let star = char('*').skip(spaces());
-        (Query::<I>::factor(), optional(star)).map(|(factor, star)| match star {
-            Some(_) => Node::Star(Box::new(factor)),
-            None => factor,
+        (Grammar::<I>::factor(), optional(Grammar::<I>::quantifier())).map(|(factor, quantifier)| {
+            match quantifier {
+                None => factor,
+                Some((0, None)) => Node::Star(Box::new(factor)),
+                Some((1, None)) => Node::Plus(Box::new(factor)),
+                Some((0, Some(1))) => Node::Optional(Box::new(factor)),
+                Some((min, max)) => Node::Repeat(Box::new(factor), min, max),
+            }
         }).parse_lazy(input).into()
     }
 
     fn subseq() -> QueryParser<Node, I> {
-        fn_parser(Query::<I>::subseq_, "subseq")
+        fn_parser(Grammar::<I>::subseq_, "pattern")
     }
     fn subseq_(input: I) -> ParseResult<Node, I> {
-        many1::<Vec<_>, _>(Query::<I>::star()).map(|stars| Node::Concat(stars)).parse_lazy(input).into()
+        many1::<Vec<_>, _>(Grammar::<I>::star()).map(|stars| Node::Concat(stars)).parse_lazy(input).into()
     }
 
     fn seq() -> QueryParser<Node, I> {
-        fn_parser(Query::<I>::seq_, "seq")
+        fn_parser(Grammar::<I>::seq_, "pattern")
     }
     fn seq_(input: I) -> ParseResult<Node, I> {
-        optional(Query::<I>::subseq()).map(|opt| match opt {
+        optional(Grammar::<I>::subseq()).map(|opt| match opt {
             Some(opt) => opt,
             None => Node::Empty,
         }).parse_lazy(input).into()
     }
 
+    /// `&` and `-` bind looser than concatenation but tighter than `|`, and associate to the left.
+    /// A `-` has to be preceded by a space so that it is not read as a column separator.
+    fn span() -> QueryParser<Node, I> {
+        fn_parser(Grammar::<I>::span_, "pattern")
+    }
+    fn span_(input: I) -> ParseResult<Node, I> {
+        let intersect = char('&').skip(Grammar::<I>::blank()).map(|_| true);
+        let difference = char('-').skip(Grammar::<I>::blank()).map(|_| false);
+        let operator = intersect.or(difference);
+        (Grammar::<I>::seq(), many::<Vec<_>, _>((operator, Grammar::<I>::seq())))
+            .map(|(first, rest)| {
+                rest.into_iter().fold(first, |left, (intersect, right)| if intersect {
+                    Node::Intersect(Box::new(left), Box::new(right))
+                } else {
+                    Node::Difference(Box::new(left), Box::new(right))
+                })
+            })
+            .parse_lazy(input).into()
+    }
+
     fn subexpr() -> QueryParser<Node, I> {
-        fn_parser(Query::<I>::subexpr_, "union")
+        fn_parser(Grammar::<I>::subexpr_, "pattern")
     }
     fn subexpr_(input: I) -> ParseResult<Node, I> {
-        let pipe_op = token('|').skip(spaces()).map(|_| |left, right| Node::Union(Box::new(left), Box::new(right)));
-        try(chainl1(Query::<I>::seq(), pipe_op)).or(Query::<I>::seq()).parse_lazy(input).into()
+        let pipe = token('|').skip(Grammar::<I>::blank());
+        sep_by1::<Vec<_>, _, _>(Grammar::<I>::span(), pipe)
+            .map(|mut nodes| if nodes.len() == 1 {
+                nodes.pop().unwrap()
+            } else {
+                Node::Union(nodes)
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn definition() -> QueryParser<(String, Node), I> {
+        fn_parser(Grammar::<I>::definition_, "definition")
+    }
+    fn definition_(input: I) -> ParseResult<(String, Node), I> {
+        let keyword = try(string("let").skip(space())).skip(Grammar::<I>::blank());
+        let name = Grammar::<I>::pattern_name().skip(Grammar::<I>::blank());
+        let equal = char('=').skip(Grammar::<I>::blank());
+        let semicolon = char(';').skip(Grammar::<I>::blank());
+        (keyword, name, equal, Grammar::<I>::subexpr(), semicolon)
+            .map(|(_, name, _, node, _)| (name, node))
+            .parse_lazy(input).into()
+    }
+
+    fn definitions() -> FnPtrParser<Vec<(String, Node)>, I> {
+        parser(Grammar::<I>::definitions_ as fn(_) -> _)
+    }
+    fn definitions_(input: I) -> ParseResult<Vec<(String, Node)>, I> {
+        Grammar::<I>::blank().with(many(Grammar::<I>::definition())).skip(eof()).parse_lazy(input).into()
+    }
+
+    fn filter() -> QueryParser<Filter, I> {
+        fn_parser(Grammar::<I>::filter_, "filter")
+    }
+    fn filter_(input: I) -> ParseResult<Filter, I> {
+        let pattern = || {
+            let paren_open = char('(').skip(Grammar::<I>::blank());
+            let paren_close = char(')').skip(Grammar::<I>::blank());
+            between(paren_open, paren_close, Grammar::<I>::subexpr())
+        };
+        let contains = || try(string("contains")).skip(Grammar::<I>::blank());
+        let positive = contains().with(pattern()).map(Filter::Contains);
+        let negative = (try(string("not")).skip(Grammar::<I>::blank()), contains())
+            .with(pattern())
+            .map(Filter::NotContains);
+        let length = try(string("length"))
+            .skip(Grammar::<I>::blank())
+            .with(Grammar::<I>::bounds().skip(Grammar::<I>::blank()))
+            .map(|(min, max)| Filter::Length(min, max));
+        positive.or(negative).or(length).parse_lazy(input).into()
+    }
+
+    fn within() -> QueryParser<Vec<Filter>, I> {
+        fn_parser(Grammar::<I>::within_, "within")
+    }
+    fn within_(input: I) -> ParseResult<Vec<Filter>, I> {
+        let keywords = (try(string("within")).skip(Grammar::<I>::blank()),
+                        string("sentence").skip(Grammar::<I>::blank()),
+                        string("where").skip(Grammar::<I>::blank()));
+        let and = try(string("and")).skip(Grammar::<I>::blank());
+        keywords.with(sep_by1(Grammar::<I>::filter(), and)).parse_lazy(input).into()
+    }
+
+    fn value() -> FnPtrParser<Query, I> {
+        parser(Grammar::<I>::value_ as fn(_) -> _)
+    }
+    fn value_(input: I) -> ParseResult<Query, I> {
+        (Grammar::<I>::blank(),
+         many(Grammar::<I>::definition()),
+         Grammar::<I>::subexpr(),
+         optional(Grammar::<I>::within()))
+            .skip(eof())
+            .map(|(_, definitions, pattern, filters)| {
+                Query {
+                    definitions: definitions,
+                    pattern: match filters {
+                        Some(filters) => Node::Within(Box::new(pattern), filters),
+                        None => pattern,
+                    },
+                }
+            })
+            .parse_lazy(input).into()
+    }
+}
+
+fn has_group(node: &Node) -> bool {
+    match *node {
+        Node::Group(..) => true,
+        Node::Union(ref nodes) |
+        Node::Concat(ref nodes) => nodes.iter().any(has_group),
+        Node::Star(ref child) |
+        Node::Plus(ref child) |
+        Node::Optional(ref child) |
+        Node::Repeat(ref child, _, _) |
+        Node::Bind(_, ref child) |
+        Node::Look(_, ref child) => has_group(child),
+        Node::Near(_, ref left, ref right) |
+        Node::Intersect(ref left, ref right) |
+        Node::Difference(ref left, ref right) => has_group(left) || has_group(right),
+        Node::Within(ref child, _) => has_group(child),
+        Node::Pattern(_) | Node::Test(_) | Node::Ref(_) | Node::Start | Node::End |
+        Node::Empty => false,
+    }
+}
+
+/// Merges a single-token pattern into the previous one if they only differ in the value of
+/// one column, e.g. `[pos=1 base=2] | [pos=1 base=3]` into `[pos=1 base={2|3}]`.
+/// The set of the previous pattern is extended in place, so that a union built from a word
+/// list stays one flat set and takes linear time to merge.
+fn merge_patterns(left: &mut Vec<(Column, Feature)>, right: &[(Column, Feature)]) -> bool {
+    /// The columns of the constraints other than wildcards, sorted, with their positions.
+    fn normalize(constraints: &[(Column, Feature)]) -> Option<Vec<(usize, usize)>> {
+        let mut normalized = vec![];
+        for (i, &(ref column, ref feature)) in constraints.iter().enumerate() {
+            match (column, feature) {
+                (_, &Feature::Any) => continue,
+                (&Column::Index(col), _) => normalized.push((col, i)),
+                (&Column::Name(_), _) => return None,
+            }
+        }
+        normalized.sort_by_key(|&(col, _)| col);
+        if normalized.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return None;
+        }
+        Some(normalized)
+    }
+    fn is_value(feature: &Feature) -> bool {
+        match *feature {
+            Feature::Id(_) | Feature::Literal(_) | Feature::Regex(_) | Feature::Set(_) => true,
+            Feature::Any | Feature::Var(_) | Feature::Not(_) => false,
+        }
     }
 
-    fn value() -> FnPtrParser<Node, I> {
-        parser(Query::<I>::value_ as fn(_) -> _)
+    let (l_columns, r_columns) = match (normalize(left), normalize(right)) {
+        (Some(l_columns), Some(r_columns)) => (l_columns, r_columns),
+        _ => return false,
+    };
+    if l_columns.len() != r_columns.len() ||
+       l_columns.iter().zip(&r_columns).any(|(l, r)| l.0 != r.0) {
+        return false;
+    }
+    let differences: Vec<(usize, usize)> = l_columns.iter()
+        .zip(&r_columns)
+        .filter(|&(&(_, l), &(_, r))| left[l].1 != right[r].1)
+        .map(|(&(_, l), &(_, r))| (l, r))
+        .collect();
+    let (l, r) = match differences.len() {
+        0 => return true,
+        1 => differences[0],
+        _ => return false,
+    };
+    if !is_value(&left[l].1) || !is_value(&right[r].1) {
+        return false;
     }
-    fn value_(input: I) -> ParseResult<Node, I> {
-        Query::<I>::subexpr().skip(eof()).parse_lazy(input).into()
+
+    // Duplicates are harmless, as the IDs of a set are deduplicated when it is compiled
+    let mut features = match mem::replace(&mut left[l].1, Feature::Any) {
+        Feature::Set(features) => features,
+        feature => vec![feature],
+    };
+    match right[r].1 {
+        Feature::Set(ref more) => features.extend(more.iter().cloned()),
+        ref feature => features.push(feature.clone()),
+    }
+    left[l].1 = Feature::Set(features);
+    true
+}
+
+fn first(node: &Node) -> &Node {
+    match *node {
+        Node::Concat(ref nodes) if !nodes.is_empty() => &nodes[0],
+        ref node => node,
+    }
+}
+
+fn last(node: &Node) -> &Node {
+    match *node {
+        Node::Concat(ref nodes) if !nodes.is_empty() => &nodes[nodes.len() - 1],
+        ref node => node,
     }
 }
 
-fn optimize(node: Node) -> Node {
+/// Splits a node into its first element and the rest, or its last element and the rest.
+fn split_off(node: Node, at_front: bool) -> (Node, Node) {
+    match node {
+        Node::Concat(mut nodes) => {
+            let element = if at_front { nodes.remove(0) } else { nodes.pop().unwrap() };
+            (element, Node::Concat(nodes))
+        }
+        node => (node, Node::Empty),
+    }
+}
+
+/// Whether a node matches at most one span from a given position, as a single token or an
+/// anchor does.
+fn is_deterministic(node: &Node) -> bool {
+    match *node {
+        Node::Pattern(_) | Node::Test(_) | Node::Start | Node::End => true,
+        _ => false,
+    }
+}
+
+/// Factors out an element shared by adjacent branches of a union:
+/// `a b | a c` becomes `a (b | c)`, and `b a | c a` becomes `(b | c) a`.
+/// A shared suffix is tried after each branch in the same order either way. If a shared prefix
+/// can match in several ways, `a (b | c)` tries `c` after the first way before `b` after the
+/// others, so only deterministic prefixes are factored out.
+/// Elements containing capture groups are left alone so that the groups keep their numbers.
+fn factor(branches: Vec<Node>, at_front: bool) -> Vec<Node> {
+    let mut runs: Vec<Vec<Node>> = vec![];
+    for branch in branches {
+        let joins = match runs.last() {
+            Some(run) => {
+                let (a, b) = if at_front {
+                    (first(&run[0]), first(&branch))
+                } else {
+                    (last(&run[0]), last(&branch))
+                };
+                a == b && !has_group(a) && (!at_front || is_deterministic(a))
+            }
+            None => false,
+        };
+        if joins {
+            runs.last_mut().unwrap().push(branch);
+        } else {
+            runs.push(vec![branch]);
+        }
+    }
+
+    runs.into_iter()
+        .map(|mut run| if run.len() == 1 {
+            run.pop().unwrap()
+        } else {
+            let mut element = None;
+            let mut rests = Vec::with_capacity(run.len());
+            for branch in run {
+                let (e, rest) = split_off(branch, at_front);
+                element = Some(e);
+                rests.push(rest);
+            }
+            let element = element.unwrap();
+            let rest = optimize(Node::Union(rests));
+            if at_front {
+                optimize(Node::Concat(vec![element, rest]))
+            } else {
+                optimize(Node::Concat(vec![rest, element]))
+            }
+        })
+        .collect()
+}
+
+pub fn optimize(node: Node) -> Node {
     match node {
         Node::Pattern(_) => node,
-        Node::Star(child) => Node::Star(Box::new(optimize(*child))),
+        Node::Star(child) => {
+            match optimize(*child) {
+                // (x*)*, (x+)* and (x?)* all match the same as x*
+                Node::Star(inner) | Node::Plus(inner) | Node::Optional(inner) => Node::Star(inner),
+                Node::Empty => Node::Empty,
+                child => Node::Star(Box::new(child)),
+            }
+        }
+        Node::Plus(child) => {
+            match optimize(*child) {
+                Node::Star(inner) | Node::Optional(inner) => Node::Star(inner),
+                Node::Plus(inner) => Node::Plus(inner),
+                Node::Empty => Node::Empty,
+                child => Node::Plus(Box::new(child)),
+            }
+        }
+        Node::Optional(child) => {
+            match optimize(*child) {
+                Node::Star(inner) | Node::Plus(inner) => Node::Star(inner),
+                Node::Optional(inner) => Node::Optional(inner),
+                Node::Empty => Node::Empty,
+                child => Node::Optional(Box::new(child)),
+            }
+        }
+        Node::Repeat(child, min, max) => Node::Repeat(Box::new(optimize(*child)), min, max),
         Node::Concat(nodes) => {
-            if nodes.len() == 1 {
-                optimize(nodes.into_iter().next().unwrap())
-            } else {
-                Node::Concat(nodes.into_iter().map(optimize).collect())
+            let mut flattened = Vec::with_capacity(nodes.len());
+            for node in nodes {
+                match optimize(node) {
+                    Node::Concat(inner) => flattened.extend(inner),
+                    Node::Empty => {}
+                    node => flattened.push(node),
+                }
+            }
+            match flattened.len() {
+                0 => Node::Empty,
+                1 => flattened.pop().unwrap(),
+                _ => Node::Concat(flattened),
             }
         },
-        Node::Union(left, right) => Node::Union(Box::new(optimize(*left)), Box::new(optimize(*right))),
-        Node::Empty => node,
+        Node::Union(nodes) => {
+            let mut branches: Vec<Node> = Vec::with_capacity(nodes.len());
+            for node in nodes {
+                let node = match optimize(node) {
+                    Node::Union(inner) => inner,
+                    node => vec![node],
+                };
+                for node in node {
+                    // Only adjacent branches are merged, as reordering alternatives would change
+                    // which one matches first
+                    let merged = match (branches.last_mut(), &node) {
+                        (Some(prev), _) if *prev == node && !has_group(prev) => true,
+                        (Some(&mut Node::Pattern(ref mut left)), &Node::Pattern(ref right)) => {
+                            merge_patterns(left, right)
+                        }
+                        _ => false,
+                    };
+                    if !merged {
+                        branches.push(node);
+                    }
+                }
+            }
+
+            let mut branches = factor(factor(branches, true), false);
+            if branches.len() > 1 && *branches.last().unwrap() == Node::Empty {
+                // (x|) tries x first and then nothing, which is exactly x?
+                branches.pop();
+                let rest = if branches.len() == 1 {
+                    branches.pop().unwrap()
+                } else {
+                    Node::Union(branches)
+                };
+                return optimize(Node::Optional(Box::new(rest)));
+            }
+            match branches.len() {
+                1 => branches.pop().unwrap(),
+                _ => Node::Union(branches),
+            }
+        },
+        Node::Group(name, child) => Node::Group(name, Box::new(optimize(*child))),
+        Node::Bind(name, child) => Node::Bind(name, Box::new(optimize(*child))),
+        Node::Look(look, child) => Node::Look(look, Box::new(optimize(*child))),
+        Node::Near(distance, left, right) => {
+            Node::Near(distance, Box::new(optimize(*left)), Box::new(optimize(*right)))
+        }
+        Node::Intersect(left, right) => {
+            Node::Intersect(Box::new(optimize(*left)), Box::new(optimize(*right)))
+        }
+        Node::Difference(left, right) => {
+            Node::Difference(Box::new(optimize(*left)), Box::new(optimize(*right)))
+        }
+        Node::Within(child, filters) => {
+            let filters = filters.into_iter()
+                .map(|filter| match filter {
+                    Filter::Contains(node) => Filter::Contains(optimize(node)),
+                    Filter::NotContains(node) => Filter::NotContains(optimize(node)),
+                    filter => filter,
+                })
+                .collect();
+            Node::Within(Box::new(optimize(*child)), filters)
+        }
+        Node::Test(_) | Node::Ref(_) | Node::Start | Node::End | Node::Empty => node,
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    /// 1-origin line and column, counted in characters.
+    pub line: usize,
+    pub column: usize,
+    /// 0-origin character offset into the query.
+    pub offset: usize,
+    pub unexpected: Option<String>,
+    pub expected: Vec<String>,
+    pub messages: Vec<String>,
+    query: String,
+}
+
+fn describe(info: &Info<char, &str>) -> String {
+    match *info {
+        Info::Token(c) => format!("`{}`", c),
+        Info::Range(range) => format!("`{}`", range),
+        Info::Owned(ref s) => s.clone(),
+        Info::Borrowed(s) => s.to_string(),
+    }
+}
+
+/// Wide characters take two cells in a terminal, so the caret has to skip two spaces for them.
+fn display_width(c: char) -> usize {
+    match c as u32 {
+        0x1100..=0x115F | 0x2E80..=0xA4CF | 0xAC00..=0xD7A3 | 0xF900..=0xFAFF |
+        0xFE30..=0xFE4F | 0xFF00..=0xFF60 | 0xFFE0..=0xFFE6 => 2,
+        _ => 1,
+    }
+}
+
+impl ParseError {
+    pub fn new(query_str: &str,
+           line: usize,
+           column: usize,
+           errors: Vec<Error<char, &str>>)
+           -> ParseError {
+        let offset = query_str.split('\n')
+            .take(line - 1)
+            .map(|line| line.chars().count() + 1)
+            .sum::<usize>() + column - 1;
+
+        let mut unexpected = None;
+        let mut expected = vec![];
+        let mut messages = vec![];
+        for error in errors {
+            match error {
+                Error::Unexpected(ref info) => unexpected = Some(describe(info)),
+                Error::Expected(ref info) => {
+                    let info = describe(info);
+                    if !expected.contains(&info) {
+                        expected.push(info);
+                    }
+                }
+                Error::Message(ref info) => messages.push(describe(info)),
+                Error::Other(ref err) => messages.push(err.to_string()),
+            }
+        }
+
+        ParseError {
+            line: line,
+            column: column,
+            offset: offset,
+            unexpected: unexpected,
+            expected: expected,
+            messages: messages,
+            query: query_str.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "syntax error at line {}, column {}", self.line, self.column)?;
+        if let Some(ref unexpected) = self.unexpected {
+            write!(f, ": unexpected {}", unexpected)?;
+        }
+        if !self.expected.is_empty() {
+            let (last, init) = self.expected.split_last().unwrap();
+            if init.is_empty() {
+                write!(f, ", expected {}", last)?;
+            } else {
+                write!(f, ", expected {} or {}", init.join(", "), last)?;
+            }
+        }
+        for message in &self.messages {
+            write!(f, ", {}", message)?;
+        }
+        let source_line: String = self.query
+            .chars()
+            .skip(self.offset + 1 - self.column)
+            .take_while(|&c| c != '\n')
+            .collect();
+        let source_line = source_line.trim_end_matches('\r');
+        let indent: usize = source_line.chars().take(self.column - 1).map(display_width).sum();
+        write!(f, "\n  {}\n  {}^", source_line, " ".repeat(indent))
+    }
+}
+
+/// Prints features in the syntax accepted inside `[...]`.
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Feature::Any => write!(f, "_"),
+            Feature::Id(id) => write!(f, "{}", id),
+            Feature::Literal(ref literal) => {
+                write!(f, "\"{}\"", literal.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            Feature::Regex(ref regex) => write!(f, "/{}/", regex.replace('/', "\\/")),
+            Feature::Var(ref name) => write!(f, "${}", name),
+            Feature::Not(ref feature) => write!(f, "!{}", feature),
+            Feature::Set(ref features) => {
+                write!(f, "{{")?;
+                for (i, feature) in features.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "|")?;
+                    }
+                    write!(f, "{}", feature)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Column::Index(col) => write!(f, "{}", col),
+            Column::Name(ref name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Predicate::Is(ref col, ref feature) => write!(f, "{}={}", col, feature),
+            Predicate::Equal(ref left, ref right) => write!(f, "{}=={}", left, right),
+            Predicate::And(ref predicates) => {
+                for (i, predicate) in predicates.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " & ")?;
+                    }
+                    match *predicate {
+                        Predicate::And(_) | Predicate::Or(_) => write!(f, "({})", predicate)?,
+                        _ => write!(f, "{}", predicate)?,
+                    }
+                }
+                Ok(())
+            }
+            Predicate::Or(ref predicates) => {
+                for (i, predicate) in predicates.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " | ")?;
+                    }
+                    match *predicate {
+                        Predicate::Or(_) => write!(f, "({})", predicate)?,
+                        _ => write!(f, "{}", predicate)?,
+                    }
+                }
+                Ok(())
+            }
+            Predicate::Not(ref predicate) => {
+                match **predicate {
+                    Predicate::Equal(ref left, ref right) => write!(f, "{}!={}", left, right),
+                    Predicate::And(_) | Predicate::Or(_) => write!(f, "!({})", predicate),
+                    _ => write!(f, "!{}", predicate),
+                }
+            }
+        }
+    }
+}
+
+/// Prints a node as canonical query text. Every pattern is printed in the `[col=feature]`
+/// form and parentheses are only added where precedence requires them, so the text parses
+/// back into the same tree as long as the tree has the shape the parser produces.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Node::Pattern(ref constraints) => {
+                write!(f, "[")?;
+                for (i, &(ref col, ref feature)) in constraints.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}={}", col, feature)?;
+                }
+                write!(f, "]")
+            }
+            Node::Test(ref predicate) => write!(f, "[{}]", predicate),
+            Node::Union(ref nodes) => {
+                for (i, node) in nodes.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " |")?;
+                        if *node != Node::Empty {
+                            write!(f, " ")?;
+                        }
+                    }
+                    match *node {
+                        Node::Union(_) => write!(f, "(?:{})", node)?,
+                        _ => write!(f, "{}", node)?,
+                    }
+                }
+                Ok(())
+            }
+            Node::Concat(ref nodes) => {
+                for (i, node) in nodes.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    match *node {
+                        Node::Union(_) | Node::Concat(_) | Node::Intersect(..) |
+                        Node::Difference(..) | Node::Empty => write!(f, "(?:{})", node)?,
+                        _ => write!(f, "{}", node)?,
+                    }
+                }
+                Ok(())
+            }
+            Node::Star(ref child) => write_factor(f, child).and_then(|_| write!(f, "*")),
+            Node::Plus(ref child) => write_factor(f, child).and_then(|_| write!(f, "+")),
+            Node::Optional(ref child) => write_factor(f, child).and_then(|_| write!(f, "?")),
+            Node::Repeat(ref child, min, max) => {
+                write_factor(f, child)?;
+                match max {
+                    Some(max) if max == min => write!(f, "{{{}}}", min),
+                    Some(max) => write!(f, "{{{},{}}}", min, max),
+                    None => write!(f, "{{{},}}", min),
+                }
+            }
+            Node::Start => write!(f, "^"),
+            Node::End => write!(f, "$"),
+            Node::Group(None, ref child) => write!(f, "({})", child),
+            Node::Group(Some(ref name), ref child) => write!(f, "(?<{}>{})", name, child),
+            Node::Bind(ref name, ref child) => {
+                write!(f, "${}:", name)?;
+                write_factor(f, child)
+            }
+            Node::Look(look, ref child) => {
+                let prefix = match look {
+                    Look::Ahead => "?=",
+                    Look::NegativeAhead => "?!",
+                    Look::Behind => "?<=",
+                    Look::NegativeBehind => "?<!",
+                };
+                write!(f, "({}{})", prefix, child)
+            }
+            Node::Near(distance, ref left, ref right) => {
+                write!(f, "NEAR/{}({}, {})", distance, left, right)
+            }
+            Node::Intersect(ref left, ref right) => write_span(f, left, "&", right),
+            Node::Difference(ref left, ref right) => write_span(f, left, "-", right),
+            Node::Ref(ref name) => write!(f, "{}", name),
+            Node::Within(ref child, ref filters) => {
+                write!(f, "{} within sentence where", child)?;
+                for (i, filter) in filters.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " and")?;
+                    }
+                    write!(f, " {}", filter)?;
+                }
+                Ok(())
+            }
+            Node::Empty => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Filter::Contains(ref node) => write!(f, "contains ({})", node),
+            Filter::NotContains(ref node) => write!(f, "not contains ({})", node),
+            Filter::Length(min, Some(max)) if min == max => write!(f, "length {{{}}}", min),
+            Filter::Length(min, Some(max)) => write!(f, "length {{{},{}}}", min, max),
+            Filter::Length(min, None) => write!(f, "length {{{},}}", min),
+        }
+    }
+}
+
+fn write_span(f: &mut fmt::Formatter, left: &Node, operator: &str, right: &Node) -> fmt::Result {
+    match *left {
+        Node::Union(_) => write!(f, "(?:{})", left)?,
+        _ => write!(f, "{}", left)?,
+    }
+    write!(f, " {} ", operator)?;
+    match *right {
+        Node::Union(_) | Node::Intersect(..) | Node::Difference(..) => write!(f, "(?:{})", right),
+        _ => write!(f, "{}", right),
+    }
+}
+
+/// Prints a node where the grammar expects a factor, i.e. before a quantifier or after `$x:`.
+fn write_factor(f: &mut fmt::Formatter, node: &Node) -> fmt::Result {
+    match *node {
+        Node::Union(_) | Node::Concat(_) | Node::Empty | Node::Star(_) | Node::Plus(_) |
+        Node::Optional(_) | Node::Repeat(..) | Node::Intersect(..) | Node::Difference(..) => {
+            write!(f, "(?:{})", node)
+        }
+        _ => write!(f, "{}", node),
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(ref name, ref node) in &self.definitions {
+            writeln!(f, "let {} = {};", name, node)?;
+        }
+        write!(f, "{}", self.pattern)
+    }
+}
+
+pub fn parse(query_str: &str) -> Result<Query, ParseError> {
+    let mut parser = Grammar::value();
+    match parser.parse(State::new(query_str)) {
+        Ok((query, _)) => Ok(query),
+        Err(err) => {
+            Err(ParseError::new(query_str,
+                                err.position.line as usize,
+                                err.position.column as usize,
+                                err.errors))
+        }
+    }
+}
+
+/// Parses a pattern library, which consists of `let` definitions only.
+pub fn parse_definitions(library_str: &str) -> Result<Vec<(String, Node)>, ParseError> {
+    let mut parser = Grammar::definitions();
+    match parser.parse(State::new(library_str)) {
+        Ok((definitions, _)) => Ok(definitions),
+        Err(err) => {
+            Err(ParseError::new(library_str,
+                                err.position.line as usize,
+                                err.position.column as usize,
+                                err.errors))
+        }
+    }
+}
+
+/// Replaces references with the patterns they name. A definition can only refer to
+/// the ones before it, which rules out recursion. Definitions in the query shadow the library.
+pub fn expand(query: Query, library: Vec<(String, Node)>) -> Result<Node, ResolveError> {
+    fn expand_node(node: Node, patterns: &HashMap<String, Node>) -> Result<Node, ResolveError> {
+        let expand_child = |child: Box<Node>| expand_node(*child, patterns).map(Box::new);
+        Ok(match node {
+            Node::Ref(name) => {
+                match patterns.get(&name) {
+                    Some(node) => node.clone(),
+                    None => return Err(ResolveError::UndefinedPattern(name)),
+                }
+            }
+            Node::Union(nodes) => {
+                Node::Union(nodes.into_iter()
+                    .map(|node| expand_node(node, patterns))
+                    .collect::<Result<_, _>>()?)
+            }
+            Node::Concat(nodes) => {
+                Node::Concat(nodes.into_iter()
+                    .map(|node| expand_node(node, patterns))
+                    .collect::<Result<_, _>>()?)
+            }
+            Node::Star(child) => Node::Star(expand_child(child)?),
+            Node::Plus(child) => Node::Plus(expand_child(child)?),
+            Node::Optional(child) => Node::Optional(expand_child(child)?),
+            Node::Repeat(child, min, max) => Node::Repeat(expand_child(child)?, min, max),
+            Node::Group(name, child) => Node::Group(name, expand_child(child)?),
+            Node::Bind(name, child) => Node::Bind(name, expand_child(child)?),
+            Node::Look(look, child) => Node::Look(look, expand_child(child)?),
+            Node::Near(distance, left, right) => {
+                Node::Near(distance, expand_child(left)?, expand_child(right)?)
+            }
+            Node::Intersect(left, right) => {
+                Node::Intersect(expand_child(left)?, expand_child(right)?)
+            }
+            Node::Difference(left, right) => {
+                Node::Difference(expand_child(left)?, expand_child(right)?)
+            }
+            Node::Within(child, filters) => {
+                let filters = filters.into_iter()
+                    .map(|filter| -> Result<Filter, ResolveError> {
+                        Ok(match filter {
+                            Filter::Contains(node) => {
+                                Filter::Contains(expand_node(node, patterns)?)
+                            }
+                            Filter::NotContains(node) => {
+                                Filter::NotContains(expand_node(node, patterns)?)
+                            }
+                            filter => filter,
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                Node::Within(expand_child(child)?, filters)
+            }
+            node @ Node::Pattern(_) |
+            node @ Node::Test(_) |
+            node @ Node::Start |
+            node @ Node::End |
+            node @ Node::Empty => node,
+        })
     }
+
+    let mut patterns = HashMap::new();
+    for (name, node) in library.into_iter().chain(query.definitions) {
+        let node = expand_node(node, &patterns)?;
+        patterns.insert(name, node);
+    }
+    expand_node(query.pattern, &patterns)
 }
 
-pub fn parse(query_str: &str) -> Node {
-    let mut parser = Query::value();
-    let (node, _) = parser.parse(State::new(query_str)).unwrap();
-    //println!("{:?}", node);
-    let opt = optimize(node);
-    //println!("{:?}", opt);
-    opt
+#[derive(Debug)]
+pub enum ResolveError {
+    NoSuchColumn(usize),
+    UnknownColumn(String),
+    UnknownFeature(String, String),
+    InvalidRegex(String, String),
+    UnboundVariable(String),
+    UndefinedPattern(String),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResolveError::NoSuchColumn(col) => write!(f, "column {} does not exist", col),
+            ResolveError::UnknownColumn(ref name) => write!(f, "column {} does not exist", name),
+            ResolveError::UnknownFeature(ref column, ref lit) => {
+                write!(f, "feature \"{}\" is not found in column {}", lit, column)
+            }
+            ResolveError::InvalidRegex(ref pattern, ref reason) => {
+                write!(f, "invalid regex /{}/: {}", pattern, reason)
+            }
+            ResolveError::UnboundVariable(ref name) => {
+                write!(f, "variable ${} is used before it is bound", name)
+            }
+            ResolveError::UndefinedPattern(ref name) => {
+                write!(f, "pattern {} is not defined before it is used", name)
+            }
+        }
+    }
+}
+
+/// Binds column names and feature literals in a parsed query to the workspace.
+pub struct Resolver<'a> {
+    schema: &'a [String],
+    features: &'a [FeatList<'a>],
+    /// Variables bound so far, in query order.
+    variables: Vec<String>,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(schema: &'a [String], features: &'a [FeatList<'a>]) -> Resolver<'a> {
+        Resolver {
+            schema: schema,
+            features: features,
+            variables: vec![],
+        }
+    }
+
+    fn column(&self, column: Column) -> Result<usize, ResolveError> {
+        match column {
+            Column::Index(col) if col < self.features.len() => Ok(col),
+            Column::Index(col) => Err(ResolveError::NoSuchColumn(col)),
+            Column::Name(name) => {
+                match self.schema.iter().position(|col_name| *col_name == name) {
+                    Some(col) => Ok(col),
+                    None => Err(ResolveError::UnknownColumn(name)),
+                }
+            }
+        }
+    }
+
+    fn feature(&self, col: usize, feature: Feature) -> Result<Feature, ResolveError> {
+        match feature {
+            Feature::Literal(lit) => {
+                match self.features[col].iter().position(|&feat| feat == lit.as_bytes()) {
+                    Some(id) => Ok(Feature::Id(id as FeatId)),
+                    // A literal that is not a feature itself, such as `"*的"`, is a glob
+                    None if lit.contains('*') => {
+                        let parts: Vec<String> = lit.split('*').map(regex::escape).collect();
+                        self.feature(col, Feature::Regex(format!("^{}$", parts.join(".*"))))
+                    }
+                    None => Err(ResolveError::UnknownFeature(self.schema[col].clone(), lit)),
+                }
+            }
+            Feature::Regex(pattern) => {
+                let regex = match Regex::new(&pattern) {
+                    Ok(regex) => regex,
+                    Err(err) => return Err(ResolveError::InvalidRegex(pattern, err.to_string())),
+                };
+                Ok(Feature::Set(self.features[col]
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, feat)| regex.is_match(feat))
+                    .map(|(id, _)| Feature::Id(id as FeatId))
+                    .collect()))
+            }
+            Feature::Var(name) => {
+                if self.variables.contains(&name) {
+                    Ok(Feature::Var(name))
+                } else {
+                    Err(ResolveError::UnboundVariable(name))
+                }
+            }
+            Feature::Not(feature) => Ok(Feature::Not(Box::new(self.feature(col, *feature)?))),
+            Feature::Set(features) => {
+                Ok(Feature::Set(features.into_iter()
+                    .map(|feature| self.feature(col, feature))
+                    .collect::<Result<_, _>>()?))
+            }
+            feature => Ok(feature),
+        }
+    }
+
+    fn predicate(&self, predicate: Predicate) -> Result<Predicate, ResolveError> {
+        Ok(match predicate {
+            Predicate::Is(column, feature) => {
+                let col = self.column(column)?;
+                Predicate::Is(Column::Index(col), self.feature(col, feature)?)
+            }
+            Predicate::Equal(left, right) => {
+                let left = self.column(left)?;
+                Predicate::Equal(Column::Index(left), Column::Index(self.column(right)?))
+            }
+            Predicate::And(predicates) => {
+                Predicate::And(predicates.into_iter()
+                    .map(|predicate| self.predicate(predicate))
+                    .collect::<Result<_, _>>()?)
+            }
+            Predicate::Or(predicates) => {
+                Predicate::Or(predicates.into_iter()
+                    .map(|predicate| self.predicate(predicate))
+                    .collect::<Result<_, _>>()?)
+            }
+            Predicate::Not(predicate) => Predicate::Not(Box::new(self.predicate(*predicate)?)),
+        })
+    }
+
+    /// Filter patterns are matched on their own, so they cannot see the query's variables.
+    fn resolve_filter(&mut self, node: Node) -> Result<Node, ResolveError> {
+        let variables = mem::replace(&mut self.variables, vec![]);
+        let resolved = self.resolve(node);
+        self.variables = variables;
+        resolved
+    }
+
+    /// Look-arounds and the right side of `&` and `-` run as sub-programs whose slots are
+    /// discarded, so the variables they bind are not visible after them.
+    fn resolve_scoped(&mut self, node: Node) -> Result<Node, ResolveError> {
+        let bound = self.variables.len();
+        let resolved = self.resolve(node);
+        self.variables.truncate(bound);
+        resolved
+    }
+
+    pub fn resolve(&mut self, node: Node) -> Result<Node, ResolveError> {
+        Ok(match node {
+            Node::Pattern(constraints) => {
+                let mut resolved = Vec::with_capacity(constraints.len());
+                for (column, feature) in constraints {
+                    let col = self.column(column)?;
+                    resolved.push((Column::Index(col), self.feature(col, feature)?));
+                }
+                Node::Pattern(resolved)
+            }
+            Node::Test(predicate) => Node::Test(self.predicate(predicate)?),
+            Node::Star(child) => Node::Star(Box::new(self.resolve(*child)?)),
+            Node::Plus(child) => Node::Plus(Box::new(self.resolve(*child)?)),
+            Node::Optional(child) => Node::Optional(Box::new(self.resolve(*child)?)),
+            Node::Repeat(child, min, max) => {
+                Node::Repeat(Box::new(self.resolve(*child)?), min, max)
+            }
+            Node::Concat(nodes) => {
+                Node::Concat(nodes.into_iter()
+                    .map(|node| self.resolve(node))
+                    .collect::<Result<_, _>>()?)
+            }
+            Node::Union(nodes) => {
+                Node::Union(nodes.into_iter()
+                    .map(|node| self.resolve(node))
+                    .collect::<Result<_, _>>()?)
+            }
+            Node::Group(name, child) => Node::Group(name, Box::new(self.resolve(*child)?)),
+            Node::Bind(name, child) => {
+                self.variables.push(name.clone());
+                Node::Bind(name, Box::new(self.resolve(*child)?))
+            }
+            Node::Look(look, child) => {
+                Node::Look(look, Box::new(self.resolve_scoped(*child)?))
+            }
+            Node::Near(distance, left, right) => {
+                let left = self.resolve(*left)?;
+                Node::Near(distance, Box::new(left), Box::new(self.resolve(*right)?))
+            }
+            Node::Intersect(left, right) => {
+                let left = self.resolve(*left)?;
+                Node::Intersect(Box::new(left), Box::new(self.resolve_scoped(*right)?))
+            }
+            Node::Difference(left, right) => {
+                let left = self.resolve(*left)?;
+                Node::Difference(Box::new(left), Box::new(self.resolve_scoped(*right)?))
+            }
+            Node::Within(child, filters) => {
+                let child = self.resolve(*child)?;
+                let filters = filters.into_iter()
+                    .map(|filter| match filter {
+                        Filter::Contains(node) => self.resolve_filter(node).map(Filter::Contains),
+                        Filter::NotContains(node) => {
+                            self.resolve_filter(node).map(Filter::NotContains)
+                        }
+                        filter => Ok(filter),
+                    })
+                    .collect::<Result<_, _>>()?;
+                Node::Within(Box::new(child), filters)
+            }
+            Node::Ref(name) => return Err(ResolveError::UndefinedPattern(name)),
+            node @ Node::Start | node @ Node::End | node @ Node::Empty => node,
+        })
+    }
 }
 
 type ISeq = LinkedList<InstCode>;
 
-pub fn compile(node: Node) -> Vec<InstCode> {
-    fn asm(node: Node, pc: usize) -> (ISeq, usize) {
+/// Collects the IDs of a resolved set, or `None` if the set contains a wildcard.
+fn feature_ids(features: Vec<Feature>) -> Option<Vec<FeatId>> {
+    let mut ids = Vec::with_capacity(features.len());
+    for feature in features {
+        match feature {
+            Feature::Any => return None,
+            Feature::Id(id) => ids.push(id),
+            Feature::Set(features) => {
+                match feature_ids(features) {
+                    Some(nested) => ids.extend(nested),
+                    None => return None,
+                }
+            }
+            feature => panic!("unsupported feature in set: {:?}", feature),
+        }
+    }
+    ids.sort();
+    ids.dedup();
+    Some(ids)
+}
+
+/// Whether the node can match without consuming any token.
+fn nullable(node: &Node) -> bool {
+    match *node {
+        Node::Pattern(_) | Node::Test(_) | Node::Ref(_) => false,
+        Node::Union(ref nodes) => nodes.iter().any(nullable),
+        Node::Concat(ref nodes) => nodes.iter().all(nullable),
+        Node::Plus(ref child) => nullable(child),
+        Node::Repeat(ref child, min, _) => min == 0 || nullable(child),
+        Node::Group(_, ref child) |
+        Node::Bind(_, ref child) => nullable(child),
+        Node::Near(_, ref left, ref right) => nullable(left) && nullable(right),
+        Node::Intersect(ref left, ref right) => nullable(left) && nullable(right),
+        Node::Difference(ref left, _) => nullable(left),
+        Node::Within(ref child, _) => nullable(child),
+        Node::Star(_) | Node::Optional(_) | Node::Look(..) | Node::Start | Node::End |
+        Node::Empty => true,
+    }
+}
+
+struct Assembler {
+    groups: Vec<Group>,
+    next_group: usize,
+    variables: HashMap<String, usize>,
+    slots: usize,
+}
+
+impl Assembler {
+    fn variable(&mut self, name: String) -> usize {
+        let slot = self.slots;
+        let slot = *self.variables.entry(name).or_insert(slot);
+        if slot == self.slots {
+            self.slots += 1;
+        }
+        slot
+    }
+
+    /// Lowers a single column constraint. Wildcards need no instruction at all.
+    fn expect(&mut self, col: usize, feature: Feature) -> Option<InstCode> {
+        match feature {
+            Feature::Any => None,
+            Feature::Id(id) => Some(InstCode::Expect(col, id)),
+            Feature::Var(name) => Some(InstCode::ExpectSame(col, self.variable(name))),
+            Feature::Set(features) => {
+                feature_ids(features).map(|ids| if ids.len() == 1 {
+                    InstCode::Expect(col, ids[0])
+                } else {
+                    InstCode::ExpectIn(col, ids)
+                })
+            }
+            Feature::Not(feature) => {
+                match *feature {
+                    Feature::Not(feature) => self.expect(col, *feature),
+                    Feature::Id(id) => Some(InstCode::ExpectNot(col, id)),
+                    Feature::Var(name) => Some(InstCode::ExpectNotSame(col, self.variable(name))),
+                    Feature::Any => Some(InstCode::ExpectIn(col, vec![])),
+                    Feature::Set(features) => {
+                        Some(match feature_ids(features) {
+                            None => InstCode::ExpectIn(col, vec![]),
+                            Some(ref ids) if ids.len() == 1 => InstCode::ExpectNot(col, ids[0]),
+                            Some(ids) => InstCode::ExpectNotIn(col, ids),
+                        })
+                    }
+                    feature => panic!("unresolved feature: {:?}", feature),
+                }
+            }
+            feature => panic!("unresolved feature: {:?}", feature),
+        }
+    }
+
+    /// Lowers a token predicate to the form the VM evaluates in one step.
+    fn predicate(&mut self, predicate: Predicate) -> Pred {
+        match predicate {
+            Predicate::Is(Column::Index(col), feature) => self.test(col, feature),
+            Predicate::Equal(Column::Index(left), Column::Index(right)) => Pred::Equal(left, right),
+            Predicate::Is(Column::Name(name), _) |
+            Predicate::Equal(Column::Name(name), _) |
+            Predicate::Equal(_, Column::Name(name)) => panic!("unresolved column: {}", name),
+            Predicate::And(predicates) => {
+                Pred::And(predicates.into_iter()
+                    .map(|predicate| self.predicate(predicate))
+                    .collect())
+            }
+            Predicate::Or(predicates) => {
+                Pred::Or(predicates.into_iter()
+                    .map(|predicate| self.predicate(predicate))
+                    .collect())
+            }
+            Predicate::Not(predicate) => Pred::Not(Box::new(self.predicate(*predicate))),
+        }
+    }
+
+    fn test(&mut self, col: usize, feature: Feature) -> Pred {
+        match feature {
+            Feature::Any => Pred::And(vec![]),
+            Feature::Id(id) => Pred::In(col, vec![id]),
+            Feature::Var(name) => Pred::Same(col, self.variable(name)),
+            Feature::Set(features) => {
+                match feature_ids(features) {
+                    Some(ids) => Pred::In(col, ids),
+                    None => Pred::And(vec![]),
+                }
+            }
+            Feature::Not(feature) => Pred::Not(Box::new(self.test(col, *feature))),
+            feature => panic!("unresolved feature: {:?}", feature),
+        }
+    }
+
+    fn group(&mut self, name: Option<String>) -> usize {
+        let index = self.next_group;
+        self.next_group += 1;
+        if index == self.groups.len() {
+            self.groups.push(Group {
+                name: name,
+                slot: self.slots,
+            });
+            self.slots += 2;
+        }
+        self.groups[index].slot
+    }
+
+    /// Assembles one copy of a repeated node. Every copy shares the same capture groups.
+    fn copy(&mut self, node: &Node, first_group: usize, pc: usize) -> (ISeq, usize) {
+        self.next_group = first_group;
+        self.asm(node.clone(), pc)
+    }
+
+    /// The span matched by the left node is recorded and checked against the right node,
+    /// which runs as a sub-program ending with its own Match.
+    fn asm_span(&mut self, left: Node, right: Node, negative: bool, pc: usize) -> (ISeq, usize) {
+        let slot = self.slots;
+        self.slots += 1;
+        let (mut l_iseq, l_pc) = self.asm(left, pc + 1);
+        let (mut r_iseq, r_pc) = self.asm(right, l_pc + 1);
+        let mut iseq = ISeq::new();
+        iseq.push_back(InstCode::Save(slot));
+        iseq.append(&mut l_iseq);
+        iseq.push_back(if negative {
+            InstCode::Subtract(slot, r_pc + 1)
+        } else {
+            InstCode::Intersect(slot, r_pc + 1)
+        });
+        iseq.append(&mut r_iseq);
+        iseq.push_back(InstCode::Match);
+        (iseq, r_pc + 1)
+    }
+
+    fn asm(&mut self, node: Node, pc: usize) -> (ISeq, usize) {
         match node {
-            Node::Pattern(feat_ids) => {
-                let mut inst_codes: ISeq = feat_ids.into_iter().enumerate().filter_map(|(i, v)| {
-                    if let Some(id) = v {
-                        Some((i, id))
-                    } else {
-                        None
+            Node::Pattern(constraints) => {
+                let mut constraints: Vec<_> = constraints.into_iter().map(|(column, feature)| {
+                    match column {
+                        Column::Index(col) => (col, feature),
+                        Column::Name(name) => panic!("unresolved column: {}", name),
                     }
-                }).map(|(col, id)| InstCode::Expect(col, id)).collect();
+                }).collect();
+                constraints.sort_by_key(|&(col, _)| col);
+                let mut inst_codes: ISeq = constraints.into_iter()
+                    .filter_map(|(col, feature)| self.expect(col, feature))
+                    .collect();
                 inst_codes.push_back(InstCode::Next);
                 let len = inst_codes.len();
                 (inst_codes, pc + len)
             },
+            Node::Test(predicate) => {
+                let mut iseq = ISeq::new();
+                iseq.push_back(InstCode::Test(self.predicate(predicate)));
+                iseq.push_back(InstCode::Next);
+                (iseq, pc + 2)
+            },
             Node::Concat(nodes) => {
                 nodes.into_iter().fold((ISeq::new(), pc), |(mut iseq, pc): (ISeq, usize), node| {
-                    let (mut iseq2, pc2) = asm(node, pc);
+                    let (mut iseq2, pc2) = self.asm(node, pc);
                     iseq.append(&mut iseq2);
                     (iseq, pc2)
                 })
             }
-            Node::Union(left, right) => {
-                let (mut a_iseq, a_pc) = asm(*left, pc + 1);
-                let (mut b_iseq, b_pc) = asm(*right, a_pc + 1);
+            Node::Union(nodes) => {
+                // Every branch but the last is tried by a Split and jumps to the end on success
+                let count = nodes.len();
+                let mut branches = Vec::with_capacity(count);
+                let mut next_pc = pc;
+                for (i, node) in nodes.into_iter().enumerate() {
+                    let last = i + 1 == count;
+                    let begin = if last { next_pc } else { next_pc + 1 };
+                    let (b_iseq, b_pc) = self.asm(node, begin);
+                    branches.push((b_iseq, b_pc));
+                    next_pc = if last { b_pc } else { b_pc + 1 };
+                }
                 let mut iseq = ISeq::new();
-                iseq.push_back(InstCode::Split(pc + 1, a_pc + 1));
-                iseq.append(&mut a_iseq);
-                iseq.push_back(InstCode::Jump(b_pc));
-                iseq.append(&mut b_iseq);
-                (iseq, b_pc)
+                for (i, (mut b_iseq, b_pc)) in branches.into_iter().enumerate() {
+                    if i + 1 == count {
+                        iseq.append(&mut b_iseq);
+                    } else {
+                        iseq.push_back(InstCode::Split(pc + iseq.len() + 1, b_pc + 1));
+                        iseq.append(&mut b_iseq);
+                        iseq.push_back(InstCode::Jump(next_pc));
+                    }
+                }
+                (iseq, next_pc)
+            },
+            Node::Star(ref child) if nullable(child) => {
+                // An iteration that consumes nothing would loop forever, so it is made to fail
+                let slot = self.slots;
+                self.slots += 1;
+                let (mut o_iseq, o_pc) = self.asm((**child).clone(), pc + 2);
+                let mut iseq = ISeq::new();
+                iseq.push_back(InstCode::Split(pc + 1, o_pc + 2));
+                iseq.push_back(InstCode::Save(slot));
+                iseq.append(&mut o_iseq);
+                iseq.push_back(InstCode::Progress(slot));
+                iseq.push_back(InstCode::Jump(pc));
+                (iseq, o_pc + 2)
             },
             Node::Star(child) => {
-                let (mut o_iseq, o_pc) = asm(*child, pc + 1);
+                let (mut o_iseq, o_pc) = self.asm(*child, pc + 1);
                 let mut iseq = ISeq::new();
                 iseq.push_back(InstCode::Split(pc + 1, o_pc + 1));
                 iseq.append(&mut o_iseq);
                 iseq.push_back(InstCode::Jump(pc));
                 (iseq, o_pc + 1)
             },
+            Node::Plus(ref child) if nullable(child) => {
+                // The first iteration may be empty, only the following ones have to progress
+                self.asm(Node::Repeat(child.clone(), 1, None), pc)
+            },
+            Node::Plus(child) => {
+                let (mut iseq, o_pc) = self.asm(*child, pc);
+                iseq.push_back(InstCode::Split(pc, o_pc + 1));
+                (iseq, o_pc + 1)
+            },
+            Node::Optional(child) => {
+                let (mut o_iseq, o_pc) = self.asm(*child, pc + 1);
+                let mut iseq = ISeq::new();
+                iseq.push_back(InstCode::Split(pc + 1, o_pc));
+                iseq.append(&mut o_iseq);
+                (iseq, o_pc)
+            },
+            Node::Repeat(child, min, max) => {
+                let first_group = self.next_group;
+                let mut iseq = ISeq::new();
+                let mut next_pc = pc;
+                for _ in 0..min {
+                    let (mut o_iseq, o_pc) = self.copy(&child, first_group, next_pc);
+                    iseq.append(&mut o_iseq);
+                    next_pc = o_pc;
+                }
+                match max {
+                    None => {
+                        let star = Node::Star(child.clone());
+                        let (mut o_iseq, o_pc) = self.copy(&star, first_group, next_pc);
+                        iseq.append(&mut o_iseq);
+                        next_pc = o_pc;
+                    }
+                    Some(max) => {
+                        // x{2,4} is lowered as x x (x (x)?)?, so every split exits to the same end
+                        let (_, len) = self.copy(&child, first_group, 0);
+                        let end = next_pc + (max - min) as usize * (len + 1);
+                        for _ in min..max {
+                            iseq.push_back(InstCode::Split(next_pc + 1, end));
+                            let (mut o_iseq, o_pc) = self.copy(&child, first_group, next_pc + 1);
+                            iseq.append(&mut o_iseq);
+                            next_pc = o_pc;
+                        }
+                    }
+                }
+                (iseq, next_pc)
+            },
+            Node::Group(name, child) => {
+                let slot = self.group(name);
+                let (mut o_iseq, o_pc) = self.asm(*child, pc + 1);
+                let mut iseq = ISeq::new();
+                iseq.push_back(InstCode::Save(slot));
+                iseq.append(&mut o_iseq);
+                iseq.push_back(InstCode::Save(slot + 1));
+                (iseq, o_pc + 1)
+            },
+            Node::Bind(name, child) => {
+                let slot = self.variable(name);
+                let (mut o_iseq, o_pc) = self.asm(*child, pc + 1);
+                o_iseq.push_front(InstCode::Save(slot));
+                (o_iseq, o_pc)
+            },
+            Node::Look(look, child) => {
+                // The sub-program runs on its own and ends with its own Match
+                let (mut o_iseq, o_pc) = self.asm(*child, pc + 1);
+                o_iseq.push_front(InstCode::Look(look, o_pc + 1));
+                o_iseq.push_back(InstCode::Match);
+                (o_iseq, o_pc + 1)
+            },
+            Node::Near(distance, left, right) => {
+                // Lowered as x gap y | y gap x; both orders share the capture groups
+                let gap = Node::Repeat(Box::new(Node::Pattern(vec![])), 0, Some(distance));
+                let left_group = self.next_group;
+                let (mut l_iseq, l_pc) = self.asm((*left).clone(), pc + 1);
+                let (mut g_iseq, g_pc) = self.asm(gap.clone(), l_pc);
+                let right_group = self.next_group;
+                let (mut r_iseq, r_pc) = self.asm((*right).clone(), g_pc);
+                let end_group = self.next_group;
+                let (mut r2_iseq, r2_pc) = self.copy(&right, right_group, r_pc + 1);
+                let (mut g2_iseq, g2_pc) = self.asm(gap, r2_pc);
+                let (mut l2_iseq, l2_pc) = self.copy(&left, left_group, g2_pc);
+                self.next_group = end_group;
+                let mut iseq = ISeq::new();
+                iseq.push_back(InstCode::Split(pc + 1, r_pc + 1));
+                iseq.append(&mut l_iseq);
+                iseq.append(&mut g_iseq);
+                iseq.append(&mut r_iseq);
+                iseq.push_back(InstCode::Jump(l2_pc));
+                iseq.append(&mut r2_iseq);
+                iseq.append(&mut g2_iseq);
+                iseq.append(&mut l2_iseq);
+                (iseq, l2_pc)
+            },
+            Node::Intersect(left, right) => self.asm_span(*left, *right, false, pc),
+            Node::Difference(left, right) => self.asm_span(*left, *right, true, pc),
+            Node::Ref(name) => panic!("unexpanded pattern: {}", name),
+            Node::Within(..) => panic!("sentence filters must be at the top of a query"),
+            Node::Start => (iter::once(InstCode::AssertStart).collect(), pc + 1),
+            Node::End => (iter::once(InstCode::AssertEnd).collect(), pc + 1),
             Node::Empty => (ISeq::new(), pc),
         }
     }
+}
 
-    let (mut iseq, _) = asm(node, 0);
+pub fn compile(node: Node) -> Program {
+    let (node, filters) = match node {
+        Node::Within(child, filters) => (*child, filters),
+        node => (node, vec![]),
+    };
+    let filters = filters.into_iter()
+        .map(|filter| match filter {
+            Filter::Contains(node) => vm::Filter::Contains(compile(node)),
+            Filter::NotContains(node) => vm::Filter::NotContains(compile(node)),
+            Filter::Length(min, max) => {
+                vm::Filter::Length(min as usize, max.map(|max| max as usize))
+            }
+        })
+        .collect();
+    let mut assembler = Assembler {
+        groups: vec![],
+        next_group: 0,
+        variables: HashMap::new(),
+        slots: 0,
+    };
+    let (mut iseq, _) = assembler.asm(node, 0);
     iseq.push_back(InstCode::Match);
-    let iseq_vec = iseq.into_iter().collect();
-    //println!("{:?}", iseq_vec);
-    iseq_vec
-}
\ No newline at end of file
+    Program {
+        inst_seq: iseq.into_iter().collect(),
+        groups: assembler.groups,
+        slots: assembler.slots,
+        filters: filters,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use index::COLS;
+    use vm::testing::{backtrack, compile_query, SENTENCES};
+
+    fn assert_equivalent(quantified: &str, desugared: &str) {
+        let left = compile_query(quantified);
+        let right = compile_query(desugared);
+        let ends = |program: &Program, tokens: &[FeatId]| -> Vec<Option<usize>> {
+            backtrack(program, tokens).into_iter().map(|(end, _)| end).collect()
+        };
+        for &tokens in SENTENCES {
+            assert_eq!(ends(&left, tokens),
+                       ends(&right, tokens),
+                       "`{}` and `{}` differ on {:?}",
+                       quantified,
+                       desugared,
+                       tokens);
+        }
+    }
+
+    #[test]
+    fn plus_is_one_then_star() {
+        assert_equivalent("1+", "1 1*");
+        assert_equivalent("2 1+ 3", "2 1 1* 3");
+    }
+
+    #[test]
+    fn optional_is_union_with_empty() {
+        assert_equivalent("1?", "(?:1|)");
+        assert_equivalent("1? 2", "(?:1|) 2");
+    }
+
+    #[test]
+    fn exact_repeat_is_concatenation() {
+        assert_equivalent("1{1}", "1");
+        assert_equivalent("1{3}", "1 1 1");
+        assert_equivalent("2 1{0} 3", "2 3");
+        assert_equivalent("1{0}", "");
+        assert_equivalent("1{0,0}", "");
+    }
+
+    #[test]
+    fn open_repeat_ends_with_star() {
+        assert_equivalent("1{2,}", "1 1 1*");
+        assert_equivalent("1{0,}", "1*");
+        assert_equivalent("(?:1|2){2,}", "(?:1|2) (?:1|2) (?:1|2)*");
+    }
+
+    #[test]
+    fn bounded_repeat_nests_optionals() {
+        assert_equivalent("1{1,3}", "1 (?:1 (?:1|)|)");
+        assert_equivalent("2 1{0,2} 2", "2 (?:1 (?:1|)|) 2");
+        assert_equivalent("(?:1 2?){1,3}", "(?:1 2?) (?:(?:1 2?) (?:(?:1 2?)|)|)");
+    }
+
+    #[test]
+    fn repeat_of_nullable_child() {
+        assert_equivalent("(?:1?)+", "(?:1?) (?:1?)*");
+        assert_equivalent("(?:1?){2,}", "(?:1?) (?:1?) (?:1?)*");
+        assert_equivalent("(?:1?){1,3}", "(?:1?) (?:(?:1?) (?:(?:1?)|)|)");
+        assert_equivalent("(?:1*){2}", "(?:1*) (?:1*)");
+    }
+
+    #[test]
+    fn star_of_nullable_child_terminates() {
+        // `compile_query` does not optimize, so the loop bodies can still match nothing
+        assert_equivalent("()*", "()");
+        assert_equivalent("(1|)*", "1*");
+        assert_equivalent("(?:1? 2?)*", "(?:1|2)*");
+    }
+
+    #[test]
+    fn bounded_repeat_splits_exit_to_the_same_end() {
+        let program = compile_query("1{1,3}");
+        assert_eq!(program.inst_seq,
+                   vec![InstCode::Expect(0, 1),
+                        InstCode::Next,
+                        InstCode::Split(3, 8),
+                        InstCode::Expect(0, 1),
+                        InstCode::Next,
+                        InstCode::Split(6, 8),
+                        InstCode::Expect(0, 1),
+                        InstCode::Next,
+                        InstCode::Match]);
+
+        let program = compile_query("(?:1 2){0,2}");
+        assert_eq!(program.inst_seq,
+                   vec![InstCode::Split(1, 10),
+                        InstCode::Expect(0, 1),
+                        InstCode::Next,
+                        InstCode::Expect(0, 2),
+                        InstCode::Next,
+                        InstCode::Split(6, 10),
+                        InstCode::Expect(0, 1),
+                        InstCode::Next,
+                        InstCode::Expect(0, 2),
+                        InstCode::Next,
+                        InstCode::Match]);
+    }
+
+    #[test]
+    fn repeated_group_captures_last_iteration() {
+        let program = compile_query("(1){2,3}");
+        assert_eq!(backtrack(&program, &[1, 1, 1])[0], (Some(3), vec![Some(2), Some(3)]));
+        assert_eq!(backtrack(&program, &[1, 1, 2])[0], (Some(2), vec![Some(1), Some(2)]));
+    }
+
+    fn parse_error(query: &str) -> ParseError {
+        match parse(query) {
+            Ok(query) => panic!("parsed as {:?}", query.pattern),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_query() {
+        let err = parse_error("1 [pos=]");
+        assert_eq!((err.line, err.column, err.offset), (1, 8, 7));
+        assert_eq!(err.unexpected, Some("`]`".to_string()));
+        assert!(err.expected.contains(&"feature".to_string()), "{:?}", err.expected);
+        assert!(err.to_string().ends_with("\n  1 [pos=]\n         ^"), "{}", err);
+
+        let err = parse_error("1\n2 (3");
+        assert_eq!((err.line, err.column, err.offset), (2, 5, 6));
+        assert_eq!(err.expected, vec!["`)`".to_string()]);
+        assert!(err.to_string().ends_with("\n  2 (3\n      ^"), "{}", err);
+
+        // Wide characters take two cells
+        let err = parse_error("\"食べ\" [");
+        assert_eq!((err.line, err.column, err.offset), (1, 7, 6));
+        assert!(err.to_string().ends_with("\n  \"食べ\" [\n          ^"), "{}", err);
+    }
+
+    #[test]
+    fn upper_bound_less_than_lower_bound_is_an_error() {
+        let err = parse_error("1{3,2}");
+        assert_eq!(err.messages, vec!["upper bound is less than lower bound".to_string()]);
+    }
+
+    #[test]
+    fn too_large_numbers_are_errors() {
+        for query in &["99999999999", "1{4294967296}", "[0=4294967296]"] {
+            let err = parse_error(query);
+            assert_eq!(err.messages, vec!["number is too large".to_string()], "{}", query);
+        }
+    }
+
+    #[test]
+    fn token_predicates_combine_columns() {
+        let starts = |query: &str| -> Vec<usize> {
+            let program = compile_query(query);
+            assert!(program.inst_seq.iter().any(|inst| match *inst {
+                InstCode::Test(_) => true,
+                _ => false,
+            }));
+            backtrack(&program, &[1, 2, 3])
+                .into_iter()
+                .enumerate()
+                .filter_map(|(sp, (end, _))| end.map(|_| sp))
+                .collect()
+        };
+        assert_eq!(starts("[0=1 | 0=3]"), vec![0, 2]);
+        assert_eq!(starts("[!0=1]"), vec![1, 2]);
+        assert_eq!(starts("[!(0=1 | 0=2) | 0=2]"), vec![1, 2]);
+        assert_eq!(starts("[0=!1 & !0=3]"), vec![1]);
+        assert_eq!(starts("[!(0=1 & 0=1)] [0=3 | !(0=2)]"), vec![1]);
+    }
+
+    #[test]
+    fn near_matches_both_orders_with_the_same_groups() {
+        let program = compile_query("NEAR/1((1), (2))");
+        assert_eq!(program.groups.len(), 2);
+        let first_match = |tokens: &[FeatId]| -> Option<(usize, Vec<Option<usize>>)> {
+            let (end, slots) = backtrack(&program, tokens).swap_remove(0);
+            let spans = program.groups
+                .iter()
+                .flat_map(|group| slots[group.slot..group.slot + 2].to_vec())
+                .collect();
+            end.map(|end| (end, spans))
+        };
+        assert_eq!(first_match(&[1, 2]), Some((2, vec![Some(0), Some(1), Some(1), Some(2)])));
+        assert_eq!(first_match(&[1, 3, 2]), Some((3, vec![Some(0), Some(1), Some(2), Some(3)])));
+        assert_eq!(first_match(&[2, 1]), Some((2, vec![Some(1), Some(2), Some(0), Some(1)])));
+        assert_eq!(first_match(&[2, 3, 1]), Some((3, vec![Some(2), Some(3), Some(0), Some(1)])));
+        assert_eq!(first_match(&[1, 3, 3, 2]), None);
+        assert_eq!(first_match(&[2, 3, 3, 1]), None);
+    }
+
+    #[test]
+    fn span_operators_compare_the_same_span() {
+        let ends = |query: &str, tokens: &[FeatId]| -> Vec<Option<usize>> {
+            backtrack(&compile_query(query), tokens).into_iter().map(|(end, _)| end).collect()
+        };
+        assert_eq!(ends("1 [] & [] 2", &[1, 2]), vec![Some(2), None, None]);
+        assert_eq!(ends("1 [] & [] 2", &[1, 3]), vec![None, None, None]);
+        // Both sides start at the same token, but `1` ends before `1 2` does
+        assert_eq!(ends("1 & 1 2", &[1, 2]), vec![None, None, None]);
+        // The left side backtracks to a shorter span that the right side matches
+        assert_eq!(ends("1 []* & []* 2", &[1, 2, 1, 3]), vec![Some(2), None, None, None, None]);
+
+        assert_eq!(ends("1 [] - [] 3", &[1, 2]), vec![Some(2), None, None]);
+        assert_eq!(ends("1 [] - [] 2", &[1, 2]), vec![None, None, None]);
+        // `1` is a different span from `1 2`, so it is not subtracted
+        assert_eq!(ends("1 - 1 2", &[1, 2]), vec![Some(1), None, None]);
+        assert_eq!(ends("1 []* - []* 2", &[1, 2]), vec![Some(1), None, None]);
+        assert_eq!(ends("1 []* - []* 2", &[1, 2, 1]), vec![Some(3), None, Some(3), None]);
+    }
+
+    #[test]
+    fn comments_and_newlines_are_blanks() {
+        let query = "# nouns followed by a particle\n\
+                     let NP = 1+;  # one or more\n\
+                     \n\
+                     NP\n\
+                     \t(?: 2 # either\n\
+                     \t  | 3 # or\n\
+                     \t)\n\
+                     # trailing comment";
+        let one_line = "let NP = 1+; NP (?:2 | 3)";
+        let (query, one_line) = (parse(query).unwrap(), parse(one_line).unwrap());
+        assert_eq!(query.definitions, one_line.definitions);
+        assert_eq!(query.pattern, one_line.pattern);
+        let library = "# a library\nlet A = 1; # first\n\nlet B = A\n  2;\n";
+        assert_eq!(parse_definitions(library).unwrap(),
+                   parse_definitions("let A = 1; let B = A 2;").unwrap());
+    }
+
+    #[test]
+    fn definitions_shadow_the_library() {
+        let library = parse_definitions("let A = 1;\nlet B = A 2;").unwrap();
+        let expanded = |query: &str| expand(parse(query).unwrap(), library.clone()).map(optimize);
+        let pattern = |query: &str| optimize(parse(query).unwrap().pattern);
+        assert_eq!(expanded("A B").unwrap(), pattern("1 1 2"));
+        // `B` keeps the `A` it was defined with
+        assert_eq!(expanded("let A = 3; A B").unwrap(), pattern("3 1 2"));
+        assert_eq!(expanded("let C = A | B; let A = 4; C A").unwrap(), pattern("(?:1 | 1 2) 4"));
+        for &(query, undefined) in &[("C", "C"), ("let C = D; let D = 1; C", "D"),
+                                     ("let C = 1 C; C", "C")] {
+            match expanded(query) {
+                Err(ResolveError::UndefinedPattern(ref name)) if name == undefined => {}
+                other => panic!("`{}` is expanded into {:?}", query, other),
+            }
+        }
+    }
+
+    #[test]
+    fn regex_or_wildcard() {
+        let pattern = |query: &str| optimize(parse(query).unwrap().pattern);
+        let slot = |feature: Feature| Node::Pattern(vec![(Column::Index(0), feature)]);
+        let regex = |regex: &str| slot(Feature::Regex(regex.to_string()));
+        assert_eq!(pattern("/[ァ-ヶ]+/"), regex("[ァ-ヶ]+"));
+        assert_eq!(pattern("/(?:しい|い)$/"), regex("(?:しい|い)$"));
+        assert_eq!(pattern("/^食べ/"), regex("^食べ"));
+        assert_eq!(pattern("[0=/]"), slot(Feature::Any));
+        assert_eq!(pattern("/*"), Node::Star(Box::new(slot(Feature::Any))));
+        assert_eq!(pattern("/-1"),
+                   Node::Pattern(vec![(Column::Index(0), Feature::Any),
+                                      (Column::Index(1), Feature::Id(1))]));
+        assert_eq!(pattern("/(1) /"), pattern("/ (1) /"));
+        assert_eq!(pattern("1-/(2) 3-/"), pattern("1-/ (2) 3-/"));
+    }
+
+    #[test]
+    fn literals_with_stars_are_globs() {
+        let schema = vec![];
+        let mut features = vec![vec![]; COLS];
+        features[0] = vec![&b"*"[..], "的".as_bytes(), "私的".as_bytes(), "的確".as_bytes(),
+                           "食べる".as_bytes(), "美しい".as_bytes(), "a.b".as_bytes()];
+        let resolve = |query: &str| {
+            Resolver::new(&schema, &features).resolve(parse(query).unwrap().pattern).unwrap()
+        };
+        assert_eq!(resolve("\"*的\""), resolve("/的$/"));
+        assert_eq!(resolve("\"的*\""), resolve("/^的/"));
+        assert_eq!(resolve("\"*し*\""), resolve("/し/"));
+        assert_eq!(resolve("\"a*b\""), resolve("[0={6}]"));
+        assert_eq!(resolve("\"*\""), resolve("0"));
+    }
+
+    #[test]
+    fn variables_bound_in_sub_programs_stay_inside() {
+        let schema = vec![];
+        let features = vec![vec![]; COLS];
+        let resolve = |query: &str| {
+            Resolver::new(&schema, &features).resolve(parse(query).unwrap().pattern)
+        };
+        for query in &["(?=$x:[0=_]) [0=$x]", "(?:[] & $x:[]) [0=$x]", "(?:[] - $x:[]) [0=$x]"] {
+            match resolve(query) {
+                Err(ResolveError::UnboundVariable(ref name)) if name == "x" => {}
+                other => panic!("`{}` resolved to {:?}", query, other),
+            }
+        }
+        assert!(resolve("$x:[0=_] (?=[0=$x])").is_ok());
+        assert!(resolve("(?=$x:[0=_] [0=$x])").is_ok());
+        assert!(resolve("(?:$x:[] & []) [0=$x]").is_ok());
+    }
+
+    #[test]
+    fn optimized_programs_match_the_same() {
+        let spans = |program: &Program, tokens: &[FeatId]| -> Vec<Vec<Option<usize>>> {
+            backtrack(program, tokens)
+                .into_iter()
+                .map(|(end, slots)| {
+                    let mut span = vec![end];
+                    for group in &program.groups {
+                        span.extend_from_slice(&slots[group.slot..group.slot + 2]);
+                    }
+                    span
+                })
+                .collect()
+        };
+        for query in &["[]* 1 | []* 2",
+                       "(?:1|1 2) 3 | (?:1|1 2) 2",
+                       "1? 2 | 1? 3",
+                       "1 2 | 1 3 | 2",
+                       "^ 1 | ^ 2",
+                       "1 (2) | 1 (3)",
+                       "2 1* | 3 1*",
+                       "(?:1 | 2) 1 | 1",
+                       "1 | 1 | 2 | 1"] {
+            let node = parse(query).unwrap().pattern;
+            let optimized = compile(optimize(node.clone()));
+            let unoptimized = compile(node);
+            for &tokens in SENTENCES {
+                assert_eq!(spans(&optimized, tokens),
+                           spans(&unoptimized, tokens),
+                           "`{}` differs on {:?} when optimized",
+                           query,
+                           tokens);
+            }
+        }
+    }
+
+    #[test]
+    fn merged_sets_stay_flat() {
+        let set = |ids: &[FeatId]| {
+            Node::Pattern(vec![(Column::Index(0),
+                                Feature::Set(ids.iter().map(|&id| Feature::Id(id)).collect()))])
+        };
+        let merged = optimize(parse("1 | 2 | 3 | 4").unwrap().pattern);
+        assert_eq!(merged, set(&[1, 2, 3, 4]));
+        assert_eq!(optimize(parse(&merged.to_string()).unwrap().pattern), merged);
+        assert_eq!(optimize(parse("[0={1|2}] | 3 | [0={4|5}]").unwrap().pattern),
+                   set(&[1, 2, 3, 4, 5]));
+
+        let words = (0..100000).map(|id| Node::Pattern(vec![(Column::Index(0), Feature::Id(id))]));
+        match optimize(Node::Union(words.collect())) {
+            Node::Pattern(ref constraints) => {
+                match constraints[0].1 {
+                    Feature::Set(ref features) => assert_eq!(features.len(), 100000),
+                    ref feature => panic!("not a set: {:?}", feature),
+                }
+            }
+            node => panic!("not merged: {:?}", node),
+        }
+    }
+
+    fn assert_round_trip(q: &str) {
+        let node = parse(q).unwrap().pattern;
+        let printed = node.to_string();
+        match parse(&printed) {
+            Ok(query) => assert_eq!(query.pattern, node, "{} printed as {}", q, printed),
+            Err(err) => panic!("{} printed as {}, which fails to parse: {:?}", q, printed, err),
+        }
+    }
+
+    #[test]
+    fn printed_queries_parse_back() {
+        for q in &["1 \"食べ\" [] [pos=1 base=2]",
+                   "[pos={1|2} base=!3] [pos!=base]",
+                   "[pos=1 & (base=2 & pos=3)] [(pos=1 | pos=2) | base=3]",
+                   "[!(pos=1 & base=2)] [!pos=1]",
+                   "/^食べ/ /的$/ [base=/[ァ-ヶ]+/]",
+                   "1 (2 | 3)* (?<x>4+) (?:5 6){1,3} 7? 8{2,} 9{3}",
+                   "^ 1 | 2 $ |",
+                   "(?=1) 2 (?!3) (?<=1 2) 3 (?<!4)",
+                   "NEAR/3(1, 2 3)",
+                   "1 [] 2 & [] 3 []",
+                   "1 []* - 1 2",
+                   "$x:[] [pos=$x]",
+                   "1 2 within sentence where contains (3) and not contains (4 | 5) and \
+                    length {2,10}"] {
+            assert_round_trip(q);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+//! A front-end for the subset of the CWB/CQP query syntax that maps onto `compiler::Node`.
+//!
+//! Values are regular expressions that have to match the whole feature, as in CQP. The `word`
+//! attribute refers to the first column, and `@` marks the token captured as `target`.
+
+use compiler::{fn_parser, token_node, Column, Feature, FnPtrParser, Node, ParseError, Predicate,
+               Query, QueryParser};
+use combine::*;
+use combine::char::*;
+use combine::primitives::{Error, Info};
+
+enum Operator {
+    Equal,
+    NotEqual,
+}
+
+struct Grammar<I>(::std::marker::PhantomData<fn(I) -> I>);
+
+impl<I> Grammar<I>
+    where I: Stream<Item = char>
+{
+    fn integer() -> QueryParser<u32, I> {
+        fn_parser(Grammar::<I>::integer_, "integer")
+    }
+    fn integer_(input: I) -> ParseResult<u32, I> {
+        many1::<String, _>(digit())
+            .and_then(|ds| -> Result<u32, Error<char, I::Range>> {
+                ds.parse::<u32>().map_err(|_| Error::Message(Info::Borrowed("number is too large")))
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn identifier() -> QueryParser<String, I> {
+        fn_parser(Grammar::<I>::identifier_, "attribute")
+    }
+    fn identifier_(input: I) -> ParseResult<String, I> {
+        (letter(), many::<String, _>(alpha_num().or(char('_'))))
+            .map(|(head, tail)| format!("{}{}", head, tail))
+            .parse_lazy(input).into()
+    }
+
+    /// `"..."` or `'...'` followed by optional flags. `%c` ignores case, `%d` is accepted
+    /// and ignored.
+    fn value() -> QueryParser<Feature, I> {
+        fn_parser(Grammar::<I>::value_, "string")
+    }
+    fn value_(input: I) -> ParseResult<Feature, I> {
+        let quoted = |quote: char| {
+            // A backslash escapes the quote; any other escape is passed on to the regex
+            let escaped = (char('\\'), any()).map(move |(_, c): (char, char)| if c == quote {
+                c.to_string()
+            } else {
+                format!("\\{}", c)
+            });
+            let plain = satisfy(move |c| c != '\\' && c != quote).map(|c: char| c.to_string());
+            between(char(quote), char(quote), many::<Vec<String>, _>(escaped.or(plain)))
+                .map(|chunks| chunks.concat())
+        };
+        let flags = optional(char('%').with(many1::<String, _>(letter())));
+        (quoted('"').or(quoted('\'')), flags)
+            .map(|(chunks, flags)| {
+                let case = match flags {
+                    Some(ref flags) if flags.contains('c') => "(?i)",
+                    _ => "",
+                };
+                Feature::Regex(format!("^{}(?:{})$", case, chunks))
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn comparison() -> QueryParser<Predicate, I> {
+        fn_parser(Grammar::<I>::comparison_, "constraint")
+    }
+    fn comparison_(input: I) -> ParseResult<Predicate, I> {
+        let equal = char('=').map(|_| Operator::Equal);
+        let not_equal = string("!=").map(|_| Operator::NotEqual);
+        (Grammar::<I>::identifier().skip(spaces()),
+         equal.or(not_equal).skip(spaces()),
+         Grammar::<I>::value().skip(spaces()))
+            .map(|(attribute, operator, value)| {
+                let column = column(attribute);
+                match operator {
+                    Operator::Equal => Predicate::Is(column, value),
+                    Operator::NotEqual => Predicate::Is(column, Feature::Not(Box::new(value))),
+                }
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn predicate() -> QueryParser<Predicate, I> {
+        fn_parser(Grammar::<I>::predicate_, "constraint")
+    }
+    fn predicate_(input: I) -> ParseResult<Predicate, I> {
+        let pipe = char('|').skip(spaces());
+        sep_by1::<Vec<_>, _, _>(Grammar::<I>::conjunction(), pipe)
+            .map(|mut predicates| if predicates.len() == 1 {
+                predicates.pop().unwrap()
+            } else {
+                Predicate::Or(predicates)
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn conjunction() -> QueryParser<Predicate, I> {
+        fn_parser(Grammar::<I>::conjunction_, "constraint")
+    }
+    fn conjunction_(input: I) -> ParseResult<Predicate, I> {
+        let ampersand = char('&').skip(spaces());
+        sep_by1::<Vec<_>, _, _>(Grammar::<I>::negation(), ampersand)
+            .map(|mut predicates| if predicates.len() == 1 {
+                predicates.pop().unwrap()
+            } else {
+                Predicate::And(predicates)
+            })
+            .parse_lazy(input).into()
+    }
+
+    fn negation() -> QueryParser<Predicate, I> {
+        fn_parser(Grammar::<I>::negation_, "constraint")
+    }
+    fn negation_(input: I) -> ParseResult<Predicate, I> {
+        let not = char('!')
+            .skip(spaces())
+            .with(Grammar::<I>::negation())
+            .map(|predicate| Predicate::Not(Box::new(predicate)));
+        let paren_open = char('(').skip(spaces());
+        let paren_close = char(')').skip(spaces());
+        let paren = between(paren_open, paren_close, Grammar::<I>::predicate());
+        not.or(paren).or(Grammar::<I>::comparison()).parse_lazy(input).into()
+    }
+
+    fn token() -> QueryParser<Node, I> {
+        fn_parser(Grammar::<I>::token_, "token")
+    }
+    fn token_(input: I) -> ParseResult<Node, I> {
+        let bracket_open = char('[').skip(spaces());
+        let bracket_close = char(']').skip(spaces());
+        between(bracket_open, bracket_close, optional(Grammar::<I>::predicate()))
+            .map(token_node)
+            .parse_lazy(input).into()
+    }
+
+    fn factor() -> QueryParser<Node, I> {
+        fn_parser(Grammar::<I>::factor_, "pattern")
+    }
+    fn factor_(input: I) -> ParseResult<Node, I> {
+        let word = Grammar::<I>::value()
+            .skip(spaces())
+            .map(|value| Node::Pattern(vec![(Column::Index(0), value)]));
+        let target = char('@')
+            .skip(spaces())
+            .with(Grammar::<I>::factor())
+            .map(|node| Node::Group(Some("target".to_string()), Box::new(node)));
+        let paren_open = char('(').skip(spaces());
+        let paren_close = char(')').skip(spaces());
+        let group = between(paren_open, paren_close, Grammar::<I>::subexpr());
+        let start = try(string("<s>")).skip(spaces()).map(|_| Node::Start);
+        let end = string("</s>").skip(spaces()).map(|_| Node::End);
+        Grammar::<I>::token()
+            .or(word)
+            .or(target)
+            .or(group)
+            .or(start)
+            .or(end)
+            .parse_lazy(input).into()
+    }
+
+    fn quantifier() -> QueryParser<(u32, Option<u32>), I> {
+        fn_parser(Grammar::<I>::quantifier_, "quantifier")
+    }
+    fn quantifier_(input: I) -> ParseResult<(u32, Option<u32>), I> {
+        let star = char('*').map(|_| (0, None));
+        let plus = char('+').map(|_| (1, None));
+        let question = char('?').map(|_| (0, Some(1)));
+        let upper = optional(char(',').with(optional(Grammar::<I>::integer())));
+        let bounds = between(char('{'), char('}'), (optional(Grammar::<I>::integer()), upper))
+            .and_then(|(min, upper)| -> Result<(u32, Option<u32>), Error<char, I::Range>> {
+                let max = match upper {
+                    None => min,
+                    Some(max) => max,
+                };
+                match (min.unwrap_or(0), max) {
+                    (min, Some(max)) if max < min => {
+                        Err(Error::Message(Info::Borrowed("upper bound is less than lower bound")))
+                    }
+                    (_, None) if min.is_none() => {
+                        Err(Error::Message(Info::Borrowed("repetition needs a bound")))
+                    }
+                    bounds => Ok(bounds),
+                }
+            });
+        star.or(plus).or(question).or(bounds).skip(spaces())
+            .parse_lazy(input).into()
+    }
+
+    fn star() -> QueryParser<Node, I> {
+        fn_parser(Grammar::<I>::star_, "pattern")
+    }
+    fn star_(input: I) -> ParseResult<Node, I> {
+        (Grammar::<I>::factor(), optional(Grammar::<I>::quantifier())).map(|(factor, quantifier)| {
+            match quantifier {
+                None => factor,
+                Some((0, None)) => Node::Star(Box::new(factor)),
+                Some((1, None)) => Node::Plus(Box::new(factor)),
+                Some((0, Some(1))) => Node::Optional(Box::new(factor)),
+                Some((min, max)) => Node::Repeat(Box::new(factor), min, max),
+            }
+        }).parse_lazy(input).into()
+    }
+
+    fn seq() -> QueryParser<Node, I> {
+        fn_parser(Grammar::<I>::seq_, "pattern")
+    }
+    fn seq_(input: I) -> ParseResult<Node, I> {
+        many1::<Vec<_>, _>(Grammar::<I>::star()).map(Node::Concat).parse_lazy(input).into()
+    }
+
+    fn subexpr() -> QueryParser<Node, I> {
+        fn_parser(Grammar::<I>::subexpr_, "pattern")
+    }
+    fn subexpr_(input: I) -> ParseResult<Node, I> {
+        let pipe = char('|').skip(spaces());
+        sep_by1::<Vec<_>, _, _>(Grammar::<I>::seq(), pipe)
+            .map(|mut nodes| if nodes.len() == 1 {
+                nodes.pop().unwrap()
+            } else {
+                Node::Union(nodes)
+            })
+            .parse_lazy(input).into()
+    }
+
+    /// Matches never cross sentences, so `within s` is accepted and has no effect.
+    fn within() -> QueryParser<(), I> {
+        fn_parser(Grammar::<I>::within_, "within")
+    }
+    fn within_(input: I) -> ParseResult<(), I> {
+        let region = try(string("sentence")).or(string("s")).skip(spaces());
+        try(string("within")).skip(spaces()).with(region).map(|_| ()).parse_lazy(input).into()
+    }
+
+    fn query() -> FnPtrParser<Query, I> {
+        parser(Grammar::<I>::query_ as fn(_) -> _)
+    }
+    fn query_(input: I) -> ParseResult<Query, I> {
+        (spaces(),
+         Grammar::<I>::subexpr(),
+         optional(Grammar::<I>::within()),
+         optional(char(';').skip(spaces())))
+            .skip(eof())
+            .map(|(_, pattern, _, _)| {
+                Query {
+                    definitions: vec![],
+                    pattern: pattern,
+                }
+            })
+            .parse_lazy(input).into()
+    }
+}
+
+fn column(attribute: String) -> Column {
+    if attribute == "word" {
+        Column::Index(0)
+    } else {
+        Column::Name(attribute)
+    }
+}
+
+pub fn parse(query_str: &str) -> Result<Query, ParseError> {
+    let mut parser = Grammar::query();
+    match parser.parse(State::new(query_str)) {
+        Ok((query, _)) => Ok(query),
+        Err(err) => {
+            Err(ParseError::new(query_str,
+                                err.position.line as usize,
+                                err.position.column as usize,
+                                err.errors))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compiler::{self, optimize};
+
+    fn assert_same(cqp: &str, vecexp: &str) {
+        match parse(cqp) {
+            Ok(query) => {
+                assert_eq!(optimize(query.pattern),
+                           optimize(compiler::parse(vecexp).unwrap().pattern),
+                           "{}",
+                           cqp)
+            }
+            Err(err) => panic!("{} fails to parse: {}", cqp, err),
+        }
+    }
+
+    fn messages(cqp: &str) -> Vec<String> {
+        match parse(cqp) {
+            Ok(query) => panic!("{} is parsed as {:?}", cqp, query.pattern),
+            Err(err) => err.messages,
+        }
+    }
+
+    #[test]
+    fn queries_map_onto_patterns() {
+        assert_same("[pos=\"名詞\"] [word=\"の\"] @[pos=\"名詞.*\"] within s;",
+                    "[pos=/^(?:名詞)$/] [0=/^(?:の)$/] (?<target>[pos=/^(?:名詞.*)$/])");
+        assert_same("\"Ab\"%c 'x'%d", "/^(?i)(?:Ab)$/ /^(?:x)$/");
+        assert_same("[pos!=\"名詞\" & !(base=\"a\" | base=\"b\")]",
+                    "[pos=!/^(?:名詞)$/ & !(base=/^(?:a)$/ | base=/^(?:b)$/)]");
+        assert_same("[]{,3} []{2,} []{2}", "[]{0,3} []{2,} []{2}");
+        assert_same("<s> [] | [] </s>", "^ [] | [] $");
+    }
+
+    #[test]
+    fn invalid_repetitions_are_errors() {
+        assert_eq!(messages("[]{,}"), vec!["repetition needs a bound".to_string()]);
+        assert_eq!(messages("[]{3,2}"), vec!["upper bound is less than lower bound".to_string()]);
+        assert_eq!(messages("[]{4294967296}"), vec!["number is too large".to_string()]);
+    }
+}
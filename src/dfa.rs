@@ -0,0 +1,370 @@
+//! A lazy DFA for programs without captures or look-arounds. States are built on demand from
+//! the instruction sequence and cached, and transitions are keyed on the values of the columns
+//! the program reads, so a cached search costs one hash lookup per token.
+//!
+//! A state is the ordered list of threads waiting to test the next token, as in the `pike`
+//! module. A `Match` cuts off the threads after it, so the DFA finds the same end as the NFA
+//! engines. When the cache fills up it is cleared, and if that happens before the states have
+//! paid for themselves the DFA gives up and `VM` runs the NFA engine instead.
+
+use index::{BodyTable, FeatId};
+use pike;
+use vm::{Equalities, InstCode, Pred, Program};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+
+/// Approximate number of bytes the cached states and transitions may use.
+const CACHE_LIMIT: usize = 2 * 1024 * 1024;
+
+/// The cache is considered to thrash when fewer tokens than this were scanned per state
+/// built since it was last cleared.
+const MIN_TOKENS_PER_STATE: usize = 10;
+
+/// Whether the program runs on this engine. Matches are reported without their groups, and
+/// look-arounds would need a search of their own at each position.
+pub fn supports(program: &Program) -> bool {
+    pike::supports(program) && program.groups.is_empty() &&
+    program.inst_seq.iter().all(|inst| match *inst {
+        InstCode::Look(..) => false,
+        _ => true,
+    })
+}
+
+/// The DFA gave up because its cache was thrashing.
+#[derive(Debug)]
+pub struct Thrashed;
+
+type StateId = usize;
+
+#[derive(Clone, Copy)]
+struct Transition {
+    /// Whether a `Match` was reached before the token was tested.
+    matched: bool,
+    next: StateId,
+}
+
+struct State {
+    /// Instructions after `Next`s, in priority order.
+    kernel: Vec<usize>,
+    transitions: HashMap<Vec<FeatId>, Transition>,
+    /// Whether a `Match` is reached at the end of the sentence.
+    end_match: Option<bool>,
+}
+
+#[derive(Default)]
+struct Cache {
+    states: Vec<State>,
+    /// Keyed on the kernel and whether the state is the start state at the beginning of a
+    /// sentence, which alone passes `AssertStart`.
+    ids: HashMap<(Vec<usize>, bool), StateId>,
+    size: usize,
+    /// Tokens scanned since the cache was last cleared.
+    tokens: usize,
+    /// Incremented whenever the cache is cleared, which invalidates every `StateId`.
+    generation: usize,
+    thrashed: bool,
+}
+
+pub struct LazyDFA<'a> {
+    program: &'a Program,
+    /// The columns the program reads, whose values make up the transition keys.
+    columns: Vec<usize>,
+    cache: RefCell<Cache>,
+}
+
+impl<'a> LazyDFA<'a> {
+    pub fn new(program: &'a Program) -> LazyDFA<'a> {
+        let mut columns = vec![];
+        for inst in &program.inst_seq {
+            match *inst {
+                InstCode::Expect(col, _) |
+                InstCode::ExpectNot(col, _) |
+                InstCode::ExpectIn(col, _) |
+                InstCode::ExpectNotIn(col, _) => columns.push(col),
+                InstCode::Test(ref pred) => collect_columns(pred, &mut columns),
+                _ => {}
+            }
+        }
+        columns.sort();
+        columns.dedup();
+
+        LazyDFA {
+            program: program,
+            columns: columns,
+            cache: RefCell::new(Cache::default()),
+        }
+    }
+
+    /// Finds the end of the match with the highest priority that starts at `sp`.
+    pub fn exec(&self,
+                sentence: &BodyTable,
+                sp: usize,
+                equalities: &Equalities)
+                -> Result<Option<usize>, Thrashed> {
+        let mut cache = self.cache.borrow_mut();
+        if cache.thrashed {
+            return Err(Thrashed);
+        }
+
+        let mut state = self.state(&mut cache, vec![0], sp == 0)?;
+        let mut sp = sp;
+        let mut matched = None;
+        let mut key = Vec::with_capacity(self.columns.len());
+        while !cache.states[state].kernel.is_empty() {
+            if sp == sentence.len() {
+                if self.end_match(&mut cache, state, sentence, equalities) {
+                    matched = Some(sp);
+                }
+                break;
+            }
+
+            key.clear();
+            key.extend(self.columns.iter().map(|&col| sentence.columns[col][sp]));
+            let cached = cache.states[state].transitions.get(&key).cloned();
+            let transition = match cached {
+                Some(transition) => transition,
+                None => self.transition(&mut cache, state, &key, sentence, sp, equalities)?,
+            };
+            if transition.matched {
+                matched = Some(sp);
+            }
+            state = transition.next;
+            sp += 1;
+            cache.tokens += 1;
+        }
+        Ok(matched)
+    }
+
+    fn state(&self,
+             cache: &mut Cache,
+             kernel: Vec<usize>,
+             at_start: bool)
+             -> Result<StateId, Thrashed> {
+        if let Some(&id) = cache.ids.get(&(kernel.clone(), at_start)) {
+            return Ok(id);
+        }
+        self.reserve(cache, 64 + kernel.len() * 2 * mem::size_of::<usize>())?;
+        let id = cache.states.len();
+        cache.states.push(State {
+            kernel: kernel.clone(),
+            transitions: HashMap::new(),
+            end_match: None,
+        });
+        cache.ids.insert((kernel, at_start), id);
+        Ok(id)
+    }
+
+    fn transition(&self,
+                  cache: &mut Cache,
+                  state: StateId,
+                  key: &[FeatId],
+                  sentence: &BodyTable,
+                  sp: usize,
+                  equalities: &Equalities)
+                  -> Result<Transition, Thrashed> {
+        let (matched, kernel) = self.step(&cache.states[state].kernel, sentence, sp, equalities);
+        let generation = cache.generation;
+        self.reserve(cache, 32 + key.len() * mem::size_of::<FeatId>())?;
+        let next = self.state(cache, kernel, false)?;
+        let transition = Transition {
+            matched: matched,
+            next: next,
+        };
+        // `state` is gone if the cache was cleared in the meantime
+        if cache.generation == generation {
+            cache.states[state].transitions.insert(key.to_vec(), transition);
+        }
+        Ok(transition)
+    }
+
+    fn end_match(&self,
+                 cache: &mut Cache,
+                 state: StateId,
+                 sentence: &BodyTable,
+                 equalities: &Equalities)
+                 -> bool {
+        if let Some(matched) = cache.states[state].end_match {
+            return matched;
+        }
+        let (matched, _) = self.step(&cache.states[state].kernel,
+                                     sentence,
+                                     sentence.len(),
+                                     equalities);
+        cache.states[state].end_match = Some(matched);
+        matched
+    }
+
+    /// Makes room for `bytes` more in the cache, clearing it if needed.
+    fn reserve(&self, cache: &mut Cache, bytes: usize) -> Result<(), Thrashed> {
+        if cache.size + bytes > CACHE_LIMIT {
+            if cache.tokens < cache.states.len() * MIN_TOKENS_PER_STATE {
+                cache.thrashed = true;
+                println_stderr!("the DFA cache is thrashing, falling back to the NFA engine.");
+                return Err(Thrashed);
+            }
+            cache.states.clear();
+            cache.ids.clear();
+            cache.size = 0;
+            cache.tokens = 0;
+            cache.generation += 1;
+        }
+        cache.size += bytes;
+        Ok(())
+    }
+
+    /// Runs the instructions that do not consume a token from each thread of the kernel, in
+    /// priority order. Returns whether a `Match` was reached, which cuts off the threads
+    /// after it, and the kernel of the threads that consume the token at `sp`.
+    fn step(&self,
+            kernel: &[usize],
+            sentence: &BodyTable,
+            sp: usize,
+            equalities: &Equalities)
+            -> (bool, Vec<usize>) {
+        let inst_seq = &self.program.inst_seq;
+        let at_end = sp == sentence.len();
+        let slots = vec![None; self.program.slots];
+        let mut visited = vec![false; inst_seq.len()];
+        let mut next = vec![];
+        let mut stack = vec![];
+
+        for &pc in kernel {
+            stack.push(pc);
+            while let Some(mut pc) = stack.pop() {
+                while pc < inst_seq.len() && !visited[pc] {
+                    visited[pc] = true;
+                    let pass = match inst_seq[pc] {
+                        InstCode::Match => return (true, next),
+                        InstCode::Next => {
+                            if !at_end {
+                                next.push(pc + 1);
+                            }
+                            break;
+                        }
+                        InstCode::Jump(next_pc) => {
+                            pc = next_pc;
+                            continue;
+                        }
+                        InstCode::Split(x, y) => {
+                            stack.push(y);
+                            pc = x;
+                            continue;
+                        }
+                        InstCode::Save(_) | InstCode::Noop => true,
+                        InstCode::AssertStart => sp == 0,
+                        InstCode::AssertEnd => at_end,
+                        InstCode::Expect(col, feat) => {
+                            !at_end && sentence.columns[col][sp] == feat
+                        }
+                        InstCode::ExpectNot(col, feat) => {
+                            !at_end && sentence.columns[col][sp] != feat
+                        }
+                        InstCode::ExpectIn(col, ref set) => {
+                            !at_end && set.binary_search(&sentence.columns[col][sp]).is_ok()
+                        }
+                        InstCode::ExpectNotIn(col, ref set) => {
+                            !at_end && set.binary_search(&sentence.columns[col][sp]).is_err()
+                        }
+                        InstCode::Test(ref pred) => {
+                            !at_end && pred.eval(sentence, sp, &slots, equalities)
+                        }
+                        InstCode::ExpectSame(..) |
+                        InstCode::ExpectNotSame(..) |
+                        InstCode::Progress(_) |
+                        InstCode::Look(..) |
+                        InstCode::Intersect(..) |
+                        InstCode::Subtract(..) => unreachable!("unsupported by the DFA"),
+                    };
+                    if !pass {
+                        break;
+                    }
+                    pc += 1;
+                }
+            }
+        }
+        (false, next)
+    }
+}
+
+fn collect_columns(pred: &Pred, columns: &mut Vec<usize>) {
+    match *pred {
+        Pred::In(col, _) | Pred::Same(col, _) => columns.push(col),
+        Pred::Equal(left, right) => {
+            columns.push(left);
+            columns.push(right);
+        }
+        Pred::Not(ref pred) => collect_columns(pred, columns),
+        Pred::And(ref preds) | Pred::Or(ref preds) => {
+            for pred in preds {
+                collect_columns(pred, columns);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+    use vm::Equalities;
+    use vm::testing::{backtrack, compile_query, sentence, SENTENCES};
+
+    /// Checks that the DFA finds the same ends as backtracking from every position of every
+    /// sentence. The states cached for one sentence are reused for the next.
+    fn assert_same_as_backtracking(program: &Program) {
+        assert!(supports(program));
+        let dfa = LazyDFA::new(program);
+        for &tokens in SENTENCES {
+            let sentence = sentence(tokens);
+            for (sp, (expected, _)) in backtrack(program, tokens).into_iter().enumerate() {
+                let end = dfa.exec(&sentence, sp, &Equalities::new()).unwrap();
+                assert_eq!(end, expected, "{:?} from {}", tokens, sp);
+            }
+        }
+    }
+
+    fn assert_query(query: &str) {
+        assert_same_as_backtracking(&compile_query(query));
+    }
+
+    #[test]
+    fn alternation_prefers_the_first_branch() {
+        assert_query("1 | 1 2");
+        assert_query("1 2 | 1");
+        assert_query("(?:1 | 1 2) 2?");
+        assert_query("1 []* | 1 2 |");
+    }
+
+    #[test]
+    fn greedy_repeats() {
+        assert_query("1* 1*");
+        assert_query("1+ (?:1 | 2)+");
+        assert_query("1{1,2} 1? 2");
+        assert_query("[]{2,} 2");
+        assert_query("(?:1 2?){2,3}");
+    }
+
+    #[test]
+    fn lazy_repeats() {
+        // `1*? 2`, with the `Split` reversed
+        let source = "loop:\n\
+                      Split done, body\n\
+                      body:\n\
+                      Expect 0 1\n\
+                      Next\n\
+                      Jump loop\n\
+                      done:\n\
+                      Match";
+        assert_same_as_backtracking(&asm::assemble(source, &[]).unwrap());
+    }
+
+    #[test]
+    fn anchors() {
+        assert_query("^ 1+ | 2 $");
+        assert_query("1* $");
+        assert_query("^ | 1");
+        assert_query("[0=!1]+ $ | 1");
+    }
+}
@@ -9,6 +9,8 @@ pub type FeatId = u32;
 pub type Feat<'a> = &'a [u8];
 pub type FeatList<'a> = Vec<Feat<'a>>;
 pub const COLS: usize = 10;
+pub const DEFAULT_SCHEMA: [&'static str; COLS] = ["surface", "pos", "pos1", "pos2", "pos3",
+                                                  "ctype", "cform", "base", "reading", "pron"];
 
 type BorrowFeat<'a> = &'a [u8];
 
@@ -16,6 +18,7 @@ pub trait IndexFileBundle {
     fn body_path(&self, usize) -> PathBuf;
     fn features_path(&self, usize) -> PathBuf;
     fn sentence_index_path(&self) -> PathBuf;
+    fn schema_path(&self) -> PathBuf;
 
     fn features_file(&self, column: usize) -> FeaturesFile {
         FeaturesFile::new(self.features_path(column))
@@ -25,6 +28,10 @@ pub trait IndexFileBundle {
         SentenceIndexFile::new(self.sentence_index_path())
     }
 
+    fn schema_file(&self) -> SchemaFile {
+        SchemaFile::new(self.schema_path())
+    }
+
     fn index_data<'a>(&self, pools: &'a mut Vec<Vec<u8>>) -> IndexData<'a> {
         println_stderr!("loading index...");
         *pools = vec![Vec::new(); 10];
@@ -35,11 +42,13 @@ pub trait IndexFileBundle {
         }
 
         let sentence_index = self.sentence_index_file().load().unwrap();
+        let schema = self.schema_file().load().unwrap();
         println_stderr!("index is loaded.");
 
         IndexData {
             features_per_column: features_per_column,
             sentence_index: sentence_index,
+            schema: schema,
         }
     }
 
@@ -78,11 +87,15 @@ pub trait IndexFileBundle {
 
 pub struct Indexer<'a> {
     bundle: &'a IndexFileBundle,
+    schema: Schema,
 }
 
 impl<'a> Indexer<'a> {
-    pub fn new(bundle: &'a IndexFileBundle) -> Indexer<'a> {
-        Indexer { bundle: bundle }
+    pub fn new(bundle: &'a IndexFileBundle, schema: Schema) -> Indexer<'a> {
+        Indexer {
+            bundle: bundle,
+            schema: schema,
+        }
     }
 
     fn open_column_file(&self, column: usize) -> io::Result<io::BufWriter<fs::File>> {
@@ -160,6 +173,9 @@ impl<'a> Indexer<'a> {
 
             let sentence_index_file = self.bundle.sentence_index_file();
             sentence_index_file.save(sentence_index)?;
+
+            let schema_file = self.bundle.schema_file();
+            schema_file.save(&self.schema)?;
         }
         Ok(())
     }
@@ -276,11 +292,58 @@ impl SentenceIndexFile {
     }
 }
 
+/// Column names, one per column of the body table.
+pub type Schema = Vec<String>;
+
+pub fn default_schema() -> Schema {
+    DEFAULT_SCHEMA.iter().map(|name| name.to_string()).collect()
+}
+
+pub struct SchemaFile {
+    path: PathBuf,
+}
+
+impl SchemaFile {
+    pub fn new(path: PathBuf) -> SchemaFile {
+        SchemaFile { path: path }
+    }
+
+    pub fn save(&self, schema: &Schema) -> io::Result<()> {
+        let mut file = fs::File::create(&self.path)?;
+        for name in schema {
+            writeln!(file, "{}", name)?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Workspaces indexed before the schema file existed fall back to the IPADIC layout.
+    pub fn load(&self) -> io::Result<Schema> {
+        let mut file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(default_schema()),
+            Err(err) => return Err(err),
+        };
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let schema: Schema = content.lines().map(|line| line.trim().to_string()).collect();
+        if schema.len() != COLS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("schema must have {} columns, but has {}",
+                                              COLS,
+                                              schema.len())));
+        }
+        Ok(schema)
+    }
+}
+
 pub struct IndexData<'a> {
     pub features_per_column: [FeatList<'a>; COLS],
     pub sentence_index: SentenceIndex,
+    pub schema: Schema,
 }
 
+#[derive(Clone, Copy)]
 pub struct BodyTable<'a> {
     pub columns: [&'a [FeatId]; COLS],
 }
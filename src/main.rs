@@ -3,6 +3,10 @@ mod macros;
 mod vm;
 mod index;
 mod compiler;
+mod pike;
+mod dfa;
+mod asm;
+mod cqp;
 mod workspace;
 
 extern crate filebuffer;
@@ -10,10 +14,15 @@ extern crate filebuffer;
 extern crate clap;
 extern crate linked_hash_map;
 extern crate combine;
+extern crate regex;
 
+use clap::ArgMatches;
+use std::fs;
+use std::io::{self, Read};
 use std::path::PathBuf;
 use std::process;
-use workspace::Workspace;
+use vm::Engine;
+use workspace::{Syntax, Workspace};
 
 fn main() {
     let matches = clap_app!(vecexp =>
@@ -23,16 +32,37 @@ fn main() {
         (@subcommand index =>
             (about: "create workspace & index")
             (@arg source: +required "Sets source file")
+            (@arg columns: -c --columns +takes_value "Sets comma-separated column names")
         )
         (@subcommand query_iseq =>
             (about: "query-iseq")
             (@arg limit: -n --limit +takes_value "Limits the number of results")
-            (@arg instseq: +multiple "InstSeq")
+            (@arg engine: -E --engine +takes_value possible_value[backtrack pike dfa]
+             "Sets the matching engine (default: backtrack)")
+            (@arg file: -f --file +takes_value conflicts_with[instseq]
+             "Reads the program from a file")
+            (@arg instseq: +multiple "InstSeq, one instruction per argument (default: stdin)")
         )
         (@subcommand query =>
             (about: "query")
             (@arg limit: -n --limit +takes_value "Limits the number of results")
-            (@arg query: "Query")
+            (@arg engine: -E --engine +takes_value possible_value[backtrack pike dfa]
+             "Sets the matching engine (default: backtrack)")
+            (@arg disassemble: -S --disassemble
+             "Prints the compiled program instead of running it")
+            (@arg syntax: -s --syntax +takes_value possible_value[vecexp cqp]
+             "Sets the query syntax (default: vecexp)")
+            (@arg file: -f --file +takes_value conflicts_with[query] "Reads the query from a file")
+            (@arg query: "Query (read from stdin if neither this nor --file is given)")
+        )
+        (@subcommand format =>
+            (about: "print query in canonical form")
+            (@arg expand: -e --expand "Expands pattern definitions")
+            (@arg optimize: -O --optimize "Prints the optimized query")
+            (@arg syntax: -s --syntax +takes_value possible_value[vecexp cqp]
+             "Sets the query syntax (default: vecexp)")
+            (@arg file: -f --file +takes_value conflicts_with[query] "Reads the query from a file")
+            (@arg query: "Query (read from stdin if neither this nor --file is given)")
         )
         (@subcommand lookup =>
             (about: "lookup feature id")
@@ -51,35 +81,80 @@ fn main() {
 
     if let Some(matches) = matches.subcommand_matches("index") {
         let source_path = PathBuf::from(matches.value_of("source").unwrap());
+        let schema = match matches.value_of("columns") {
+            Some(columns) => columns.split(',').map(|name| name.to_string()).collect(),
+            None => index::default_schema(),
+        };
         println_stderr!("indexing...");
-        try!(workspace.create_index(source_path));
+        try!(workspace.create_index(source_path, schema));
         println_stderr!("fully indexed.")
     } else if let Some(matches) = matches.subcommand_matches("query_iseq") {
-        let opcodes: Vec<_> =
-            matches.values_of("instseq").unwrap().map(|s| s.to_string()).collect();
+        let source = match matches.values_of("instseq") {
+            Some(instseq) => instseq.collect::<Vec<_>>().join("\n"),
+            None => try!(read_source(matches.value_of("file"))),
+        };
         let limit: Option<usize> = matches.value_of("limit").map({
             |v| v.parse::<usize>().unwrap()
         });
-        try!(workspace.search2(opcodes, limit));
+        try!(workspace.search2(&source, engine(matches), limit));
     } else if let Some(matches) = matches.subcommand_matches("query") {
-        let query = matches.value_of("query").unwrap();
+        let query = try!(read_query(matches));
         let limit: Option<usize> = matches.value_of("limit").map({
             |v| v.parse::<usize>().unwrap()
         });
-        let tree = compiler::parse(query);
-        let iseq = compiler::compile(tree);
-        try!(workspace.search(iseq, limit));
+        if matches.is_present("disassemble") {
+            print!("{}", try!(workspace.disassemble(&query, syntax(matches))));
+        } else {
+            try!(workspace.query(&query, syntax(matches), engine(matches), limit));
+        }
+    } else if let Some(matches) = matches.subcommand_matches("format") {
+        let query = try!(read_query(matches));
+        let expand = matches.is_present("expand");
+        let optimize = matches.is_present("optimize");
+        println!("{}", try!(workspace.format(&query, syntax(matches), expand, optimize)));
     } else if let Some(matches) = matches.subcommand_matches("lookup") {
-        let column: usize = matches.value_of("column").unwrap().parse::<usize>().unwrap();
+        let column = try!(workspace.column(matches.value_of("column").unwrap()));
         let feature = String::from(matches.value_of("feature").unwrap());
         match try!(workspace.lookup(column, feature)) {
             Some(feat_id) => println!("{}", feat_id),
             None => println_stderr!("not found."),
         }
     } else if let Some(matches) = matches.subcommand_matches("decode") {
-        let column: usize = matches.value_of("column").unwrap().parse::<usize>().unwrap();
+        let column = try!(workspace.column(matches.value_of("column").unwrap()));
         let feature = matches.value_of("feature_id").unwrap().parse::<u32>().unwrap();
         let feat = try!(workspace.decode(column, feature));
         println!("{}", feat);
     }
 }
+
+fn syntax(matches: &ArgMatches) -> Syntax {
+    match matches.value_of("syntax") {
+        Some("cqp") => Syntax::Cqp,
+        _ => Syntax::Vecexp,
+    }
+}
+
+fn engine(matches: &ArgMatches) -> Engine {
+    match matches.value_of("engine") {
+        Some("pike") => Engine::Pike,
+        Some("dfa") => Engine::Dfa,
+        _ => Engine::Backtrack,
+    }
+}
+
+fn read_query(matches: &ArgMatches) -> io::Result<String> {
+    match matches.value_of("query") {
+        Some(query) => Ok(query.to_string()),
+        None => read_source(matches.value_of("file")),
+    }
+}
+
+/// Reads the file, or stdin if no path is given.
+fn read_source(path: Option<&str>) -> io::Result<String> {
+    let mut source = String::new();
+    match path {
+        Some(path) => fs::File::open(path)?.read_to_string(&mut source)?,
+        None => io::stdin().read_to_string(&mut source)?,
+    };
+    Ok(source)
+}
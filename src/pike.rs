@@ -0,0 +1,380 @@
+//! An engine that simulates a program as an NFA, advancing every thread one token at a time
+//! (a Pike VM). A search takes O(instructions × tokens) time for each start position and its
+//! depth does not grow with the sentence, unlike the backtracking `VM::int_exec`.
+//!
+//! Threads that reach the same instruction at the same position are merged, keeping the one
+//! with the highest priority. This gives the same matches and captures as backtracking as long
+//! as the future of a thread does not depend on its slots, which `supports` checks.
+
+use index::BodyTable;
+use vm::{Equalities, InstCode, Pred, Program};
+
+use std::cell::RefCell;
+use std::mem;
+
+/// Why the program does not run on this engine, if it does not. Backreferences, the checks
+/// of loops whose body can match nothing and span operators read slots that threads may
+/// disagree on when they are merged.
+pub fn unsupported(program: &Program) -> Option<&'static str> {
+    fn reads_slot(pred: &Pred) -> bool {
+        match *pred {
+            Pred::Same(..) => true,
+            Pred::Not(ref pred) => reads_slot(pred),
+            Pred::And(ref preds) | Pred::Or(ref preds) => preds.iter().any(reads_slot),
+            Pred::In(..) | Pred::Equal(..) => false,
+        }
+    }
+    program.inst_seq.iter().filter_map(|inst| match *inst {
+        InstCode::ExpectSame(..) |
+        InstCode::ExpectNotSame(..) => Some("a backreference"),
+        InstCode::Test(ref pred) if reads_slot(pred) => Some("a backreference"),
+        InstCode::Progress(_) => Some("a loop whose body can match nothing"),
+        InstCode::Intersect(..) |
+        InstCode::Subtract(..) => Some("a span operator"),
+        _ => None,
+    }).next()
+}
+
+/// Whether the program runs on this engine.
+pub fn supports(program: &Program) -> bool {
+    unsupported(program).is_none()
+}
+
+/// The threads of one position, in priority order. Each instruction holds at most one thread.
+struct Threads {
+    dense: Vec<usize>,
+    sparse: Vec<usize>,
+    len: usize,
+    /// The slots of the thread at each instruction, `slot_count` per instruction.
+    slots: Vec<Option<usize>>,
+    slot_count: usize,
+}
+
+impl Threads {
+    fn new(size: usize, slot_count: usize) -> Threads {
+        Threads {
+            dense: vec![0; size],
+            sparse: vec![0; size],
+            len: 0,
+            slots: vec![None; size * slot_count],
+            slot_count: slot_count,
+        }
+    }
+
+    fn contains(&self, pc: usize) -> bool {
+        let i = self.sparse[pc];
+        i < self.len && self.dense[i] == pc
+    }
+
+    fn insert(&mut self, pc: usize) {
+        self.dense[self.len] = pc;
+        self.sparse[pc] = self.len;
+        self.len += 1;
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn slots(&self, pc: usize) -> &[Option<usize>] {
+        &self.slots[pc * self.slot_count..(pc + 1) * self.slot_count]
+    }
+
+    fn slots_mut(&mut self, pc: usize) -> &mut [Option<usize>] {
+        &mut self.slots[pc * self.slot_count..(pc + 1) * self.slot_count]
+    }
+}
+
+enum Frame {
+    Explore(usize),
+    /// Undoes a `Save` once the instructions after it have been explored.
+    Restore(usize, Option<usize>),
+}
+
+struct Cache {
+    clist: Threads,
+    nlist: Threads,
+    stack: Vec<Frame>,
+    thread: Vec<Option<usize>>,
+}
+
+impl Cache {
+    fn new(program: &Program) -> Cache {
+        let size = program.inst_seq.len();
+        Cache {
+            clist: Threads::new(size, program.slots),
+            nlist: Threads::new(size, program.slots),
+            stack: vec![],
+            thread: vec![None; program.slots],
+        }
+    }
+}
+
+pub struct PikeVM<'a> {
+    program: &'a Program,
+    /// Look-arounds start nested searches, so there is one cache per level in use.
+    caches: RefCell<Vec<Cache>>,
+}
+
+impl<'a> PikeVM<'a> {
+    pub fn new(program: &'a Program) -> PikeVM<'a> {
+        PikeVM {
+            program: program,
+            caches: RefCell::new(vec![]),
+        }
+    }
+
+    /// Finds the match with the highest priority that starts at `sp`, like `VM::int_exec`.
+    pub fn exec(&self,
+                sentence: &BodyTable,
+                sp: usize,
+                equalities: &Equalities,
+                slots: &mut [Option<usize>])
+                -> Option<usize> {
+        self.run(sentence, 0, sp, None, equalities, slots)
+    }
+
+    fn run(&self,
+           sentence: &BodyTable,
+           pc: usize,
+           sp: usize,
+           end: Option<usize>,
+           equalities: &Equalities,
+           slots: &mut [Option<usize>])
+           -> Option<usize> {
+        let cache = self.caches.borrow_mut().pop();
+        let mut cache = cache.unwrap_or_else(|| Cache::new(self.program));
+        let ret = self.run_with(&mut cache, sentence, pc, sp, end, equalities, slots);
+        self.caches.borrow_mut().push(cache);
+        ret
+    }
+
+    fn run_with(&self,
+                cache: &mut Cache,
+                sentence: &BodyTable,
+                pc: usize,
+                sp: usize,
+                end: Option<usize>,
+                equalities: &Equalities,
+                slots: &mut [Option<usize>])
+                -> Option<usize> {
+        let Cache { ref mut clist, ref mut nlist, ref mut stack, ref mut thread } = *cache;
+        clist.clear();
+        nlist.clear();
+        thread.copy_from_slice(slots);
+        self.add(clist, stack, thread, pc, sp, sentence, equalities);
+
+        let mut sp = sp;
+        let mut matched = None;
+        while clist.len > 0 {
+            for i in 0..clist.len {
+                let pc = clist.dense[i];
+                match self.program.inst_seq[pc] {
+                    InstCode::Match => {
+                        if end.map_or(true, |end| end == sp) {
+                            // Threads after this one have lower priority than the match
+                            slots.copy_from_slice(clist.slots(pc));
+                            matched = Some(sp);
+                            break;
+                        }
+                    }
+                    InstCode::Next => {
+                        if sp < sentence.len() {
+                            thread.copy_from_slice(clist.slots(pc));
+                            self.add(nlist, stack, thread, pc + 1, sp + 1, sentence, equalities);
+                        }
+                    }
+                    // The instructions passed on the way are only listed to visit them once
+                    _ => {}
+                }
+            }
+            mem::swap(clist, nlist);
+            nlist.clear();
+            sp += 1;
+        }
+        matched
+    }
+
+    /// Follows every instruction that does not consume a token, in priority order, and leaves
+    /// threads at `Next` and `Match`.
+    fn add(&self,
+           list: &mut Threads,
+           stack: &mut Vec<Frame>,
+           thread: &mut [Option<usize>],
+           pc: usize,
+           sp: usize,
+           sentence: &BodyTable,
+           equalities: &Equalities) {
+        let inst_seq = &self.program.inst_seq;
+        let token = |col: usize| if sp < sentence.len() {
+            Some(sentence.columns[col][sp])
+        } else {
+            None
+        };
+
+        stack.push(Frame::Explore(pc));
+        while let Some(frame) = stack.pop() {
+            let mut pc = match frame {
+                Frame::Explore(pc) => pc,
+                Frame::Restore(slot, saved) => {
+                    thread[slot] = saved;
+                    continue;
+                }
+            };
+            while pc < inst_seq.len() && !list.contains(pc) {
+                list.insert(pc);
+                let pass = match inst_seq[pc] {
+                    InstCode::Next | InstCode::Match => {
+                        list.slots_mut(pc).copy_from_slice(thread);
+                        break;
+                    }
+                    InstCode::Jump(next_pc) => {
+                        pc = next_pc;
+                        continue;
+                    }
+                    InstCode::Split(x, y) => {
+                        stack.push(Frame::Explore(y));
+                        pc = x;
+                        continue;
+                    }
+                    InstCode::Save(slot) => {
+                        stack.push(Frame::Restore(slot, thread[slot]));
+                        thread[slot] = Some(sp);
+                        true
+                    }
+                    InstCode::Look(look, next_pc) => {
+                        // Captures made inside a look-around are discarded
+                        let mut look_slots = thread.to_vec();
+                        let found = if look.is_behind() {
+                            (0..sp + 1).any(|begin| {
+                                let end = Some(sp);
+                                self.run(sentence, pc + 1, begin, end, equalities, &mut look_slots)
+                                    .is_some()
+                            })
+                        } else {
+                            self.run(sentence, pc + 1, sp, None, equalities, &mut look_slots)
+                                .is_some()
+                        };
+                        if found != look.is_negative() {
+                            pc = next_pc;
+                            continue;
+                        }
+                        false
+                    }
+                    InstCode::Noop => true,
+                    InstCode::AssertStart => sp == 0,
+                    InstCode::AssertEnd => sp == sentence.len(),
+                    InstCode::Expect(col, feat) => token(col) == Some(feat),
+                    InstCode::ExpectNot(col, feat) => token(col).map_or(false, |id| id != feat),
+                    InstCode::ExpectIn(col, ref set) => {
+                        token(col).map_or(false, |id| set.binary_search(&id).is_ok())
+                    }
+                    InstCode::ExpectNotIn(col, ref set) => {
+                        token(col).map_or(false, |id| set.binary_search(&id).is_err())
+                    }
+                    InstCode::Test(ref pred) => {
+                        sp < sentence.len() && pred.eval(sentence, sp, thread, equalities)
+                    }
+                    InstCode::ExpectSame(..) |
+                    InstCode::ExpectNotSame(..) |
+                    InstCode::Progress(_) |
+                    InstCode::Intersect(..) |
+                    InstCode::Subtract(..) => unreachable!("unsupported by the Pike VM"),
+                };
+                if !pass {
+                    break;
+                }
+                pc += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+    use vm::Equalities;
+    use vm::testing::{backtrack, compile_query, sentence, SENTENCES};
+
+    /// Checks that the Pike VM finds the same end and slots as backtracking from every
+    /// position of every sentence.
+    fn assert_same_as_backtracking(program: &Program) {
+        assert!(supports(program));
+        let pike = PikeVM::new(program);
+        for &tokens in SENTENCES {
+            let sentence = sentence(tokens);
+            for (sp, expected) in backtrack(program, tokens).into_iter().enumerate() {
+                let mut slots = vec![None; program.slots];
+                let end = pike.exec(&sentence, sp, &Equalities::new(), &mut slots);
+                assert_eq!((end, slots), expected, "{:?} from {}", tokens, sp);
+            }
+        }
+    }
+
+    fn assert_query(query: &str) {
+        assert_same_as_backtracking(&compile_query(query));
+    }
+
+    #[test]
+    fn alternation_prefers_the_first_branch() {
+        assert_query("(1) | (1 2)");
+        assert_query("(1 2) | (1)");
+        assert_query("(?:(1) | (1 2)) (2)?");
+        assert_query("(1 []*) | (1 2) |");
+    }
+
+    #[test]
+    fn greedy_repeats() {
+        assert_query("(1*) (1*)");
+        assert_query("(1+) (1 | 2)+");
+        assert_query("(1{1,2}) (1?) 2");
+        assert_query("([]{2,}) (2)");
+    }
+
+    #[test]
+    fn lazy_repeats() {
+        // `(1*?)` followed by `(1*)`, with the `Split`s reversed
+        let source = ".group 0\n\
+                      .group 2\n\
+                      Save 0\n\
+                      lazy:\n\
+                      Split lazy_done, lazy_body\n\
+                      lazy_body:\n\
+                      Expect 0 1\n\
+                      Next\n\
+                      Jump lazy\n\
+                      lazy_done:\n\
+                      Save 1\n\
+                      Save 2\n\
+                      greedy:\n\
+                      Split greedy_body, greedy_done\n\
+                      greedy_body:\n\
+                      Expect 0 1\n\
+                      Next\n\
+                      Jump greedy\n\
+                      greedy_done:\n\
+                      Save 3\n\
+                      Expect 0 2\n\
+                      Next\n\
+                      Match";
+        assert_same_as_backtracking(&asm::assemble(source, &[]).unwrap());
+    }
+
+    #[test]
+    fn groups_inside_loops_keep_the_last_iteration() {
+        assert_query("(?:(1) | (2))*");
+        assert_query("(?:(1) (2)?)+ 3?");
+        assert_query("(?<x>1 | 2){2,3}");
+    }
+
+    #[test]
+    fn look_arounds() {
+        assert_query("(?<=1) (2)");
+        assert_query("(?<=(1) 2) (1+)");
+        assert_query("(?<!2) (1+)");
+        assert_query("(?=1 2) (1)");
+        assert_query("(?!1) ([])");
+        assert_query("^ (1+) | (2) $");
+    }
+}
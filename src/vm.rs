@@ -1,59 +1,492 @@
-use index::{BodyTable, FeatId, IndexData};
+use dfa::{self, LazyDFA};
+use index::{BodyTable, FeatId, IndexData, COLS};
+use pike::{self, PikeVM};
 
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum InstCode {
     Expect(usize, FeatId),
+    ExpectNot(usize, FeatId),
+    /// The set is sorted so that it can be binary-searched.
+    ExpectIn(usize, Vec<FeatId>),
+    ExpectNotIn(usize, Vec<FeatId>),
+    /// Compares a column with the same column of the token whose position is in a slot.
+    ExpectSame(usize, usize),
+    ExpectNotSame(usize, usize),
     Match,
     Jump(usize),
     Next,
     Split(usize, usize),
     Noop,
+    AssertStart,
+    AssertEnd,
+    /// Records the current token position into a slot.
+    Save(usize),
+    /// Fails unless the current token position differs from the one in the slot.
+    Progress(usize),
+    /// Runs the zero-width sub-program that starts at the next instruction,
+    /// then continues at the given pc.
+    Look(Look, usize),
+    /// Runs the sub-program that starts at the next instruction over the span from the
+    /// position in the slot to the current position, then continues at the given pc.
+    Intersect(usize, usize),
+    /// Same as `Intersect`, but continues only if the sub-program does not match the span.
+    Subtract(usize, usize),
+    /// Evaluates a boolean expression over the columns of the current token.
+    Test(Pred),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Pred {
+    /// The set is sorted so that it can be binary-searched.
+    In(usize, Vec<FeatId>),
+    /// Same as `ExpectSame`; an unbound slot never matches.
+    Same(usize, usize),
+    /// The two columns of the token have the same string.
+    Equal(usize, usize),
+    Not(Box<Pred>),
+    And(Vec<Pred>),
+    Or(Vec<Pred>),
+}
+
+/// For each pair of columns compared by `Pred::Equal`, maps every feature of the first
+/// column to the feature of the second column with the same string, if any.
+pub type Equalities = HashMap<(usize, usize), Vec<Option<FeatId>>>;
+
+impl Pred {
+    pub fn eval(&self,
+            sentence: &BodyTable,
+            sp: usize,
+            slots: &[Option<usize>],
+            equalities: &Equalities)
+            -> bool {
+        match *self {
+            Pred::In(col, ref set) => set.binary_search(&sentence.columns[col][sp]).is_ok(),
+            Pred::Same(col, slot) => {
+                let column = sentence.columns[col];
+                slots[slot].map_or(false, |bound| column[sp] == column[bound])
+            }
+            Pred::Equal(left, right) => {
+                let id = sentence.columns[left][sp];
+                equalities[&(left, right)][id as usize] == Some(sentence.columns[right][sp])
+            }
+            Pred::Not(ref pred) => !pred.eval(sentence, sp, slots, equalities),
+            Pred::And(ref preds) => {
+                preds.iter().all(|pred| pred.eval(sentence, sp, slots, equalities))
+            }
+            Pred::Or(ref preds) => {
+                preds.iter().any(|pred| pred.eval(sentence, sp, slots, equalities))
+            }
+        }
+    }
+
+    fn collect_equalities(&self, pairs: &mut Vec<(usize, usize)>) {
+        match *self {
+            Pred::Equal(left, right) => pairs.push((left, right)),
+            Pred::Not(ref pred) => pred.collect_equalities(pairs),
+            Pred::And(ref preds) | Pred::Or(ref preds) => {
+                for pred in preds {
+                    pred.collect_equalities(pairs);
+                }
+            }
+            Pred::In(..) | Pred::Same(..) => {}
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Look {
+    Ahead,
+    NegativeAhead,
+    Behind,
+    NegativeBehind,
+}
+
+impl Look {
+    pub fn is_negative(&self) -> bool {
+        *self == Look::NegativeAhead || *self == Look::NegativeBehind
+    }
+
+    pub fn is_behind(&self) -> bool {
+        *self == Look::Behind || *self == Look::NegativeBehind
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Group {
+    pub name: Option<String>,
+    /// The group spans from `slots[slot]` to `slots[slot + 1]`.
+    pub slot: usize,
+}
+
+/// A condition on a whole sentence, checked before searching it.
+#[derive(Debug, PartialEq)]
+pub enum Filter {
+    Contains(Program),
+    NotContains(Program),
+    /// The number of tokens is at least the first bound and at most the second one.
+    Length(usize, Option<usize>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    pub inst_seq: Vec<InstCode>,
+    pub groups: Vec<Group>,
+    pub slots: usize,
+    pub filters: Vec<Filter>,
+}
+
+#[derive(Debug)]
+pub struct ValidationError {
+    /// The program counter of the offending instruction, if there is one.
+    pub pc: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pc {
+            Some(pc) => write!(f, "invalid program at pc {}: {}", pc, self.message),
+            None => write!(f, "invalid program: {}", self.message),
+        }
+    }
+}
+
+fn check_column(col: usize) -> Result<(), String> {
+    if col < COLS {
+        Ok(())
+    } else {
+        Err(format!("column {} does not exist", col))
+    }
+}
+
+fn check_features(index_data: &IndexData, col: usize, ids: &[FeatId]) -> Result<(), String> {
+    check_column(col)?;
+    let size = index_data.features_per_column[col].len();
+    match ids.iter().find(|&&id| id as usize >= size) {
+        Some(id) => {
+            Err(format!("feature {} is out of column {}, which has {} features", id, col, size))
+        }
+        None => Ok(()),
+    }
+}
+
+impl Program {
+    /// Checks everything `VM` relies on without checking it at run time: jump targets,
+    /// columns, feature ids and slots are in range, a `Match` can be reached, and groups do not
+    /// end before they start.
+    pub fn validate(&self, index_data: &IndexData) -> Result<(), ValidationError> {
+        for (pc, inst) in self.inst_seq.iter().enumerate() {
+            self.check_inst(inst, index_data).map_err(|message| {
+                ValidationError {
+                    pc: Some(pc),
+                    message: message,
+                }
+            })?;
+        }
+        for group in &self.groups {
+            if group.slot + 1 >= self.slots {
+                return Err(ValidationError {
+                    pc: None,
+                    message: format!("group at slot {} is out of {} slots", group.slot, self.slots),
+                });
+            }
+        }
+        if !self.reaches_match() {
+            return Err(ValidationError {
+                pc: None,
+                message: "no Match is reachable from the start".to_string(),
+            });
+        }
+        if let Some((pc, group)) = self.group_ending_before_start() {
+            return Err(ValidationError {
+                pc: Some(pc),
+                message: format!("group at slot {} can end before it starts", group.slot),
+            });
+        }
+        for (i, filter) in self.filters.iter().enumerate() {
+            match *filter {
+                Filter::Contains(ref program) |
+                Filter::NotContains(ref program) => {
+                    program.validate(index_data).map_err(|err| {
+                        ValidationError {
+                            pc: err.pc,
+                            message: format!("in filter {}: {}", i, err.message),
+                        }
+                    })?
+                }
+                Filter::Length(min, Some(max)) if max < min => {
+                    return Err(ValidationError {
+                        pc: None,
+                        message: format!("filter {} has a lower bound greater than the upper one",
+                                         i),
+                    });
+                }
+                Filter::Length(..) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn check_slot(&self, slot: usize) -> Result<(), String> {
+        if slot < self.slots {
+            Ok(())
+        } else {
+            Err(format!("slot {} is out of {} slots", slot, self.slots))
+        }
+    }
+
+    fn check_target(&self, target: usize) -> Result<(), String> {
+        if target < self.inst_seq.len() {
+            Ok(())
+        } else {
+            Err(format!("target {} is past the end of {} instructions",
+                        target,
+                        self.inst_seq.len()))
+        }
+    }
+
+    fn check_pred(&self, pred: &Pred, index_data: &IndexData) -> Result<(), String> {
+        match *pred {
+            Pred::In(col, ref ids) => check_features(index_data, col, ids),
+            Pred::Same(col, slot) => check_column(col).and_then(|_| self.check_slot(slot)),
+            Pred::Equal(left, right) => check_column(left).and_then(|_| check_column(right)),
+            Pred::Not(ref pred) => self.check_pred(pred, index_data),
+            Pred::And(ref preds) | Pred::Or(ref preds) => {
+                preds.iter().map(|pred| self.check_pred(pred, index_data)).collect()
+            }
+        }
+    }
+
+    fn check_inst(&self, inst: &InstCode, index_data: &IndexData) -> Result<(), String> {
+        match *inst {
+            InstCode::Expect(col, id) |
+            InstCode::ExpectNot(col, id) => check_features(index_data, col, &[id]),
+            InstCode::ExpectIn(col, ref ids) |
+            InstCode::ExpectNotIn(col, ref ids) => check_features(index_data, col, ids),
+            InstCode::ExpectSame(col, slot) |
+            InstCode::ExpectNotSame(col, slot) => {
+                check_column(col).and_then(|_| self.check_slot(slot))
+            }
+            InstCode::Jump(x) => self.check_target(x),
+            InstCode::Split(x, y) => self.check_target(x).and_then(|_| self.check_target(y)),
+            InstCode::Save(slot) |
+            InstCode::Progress(slot) => self.check_slot(slot),
+            InstCode::Look(_, next_pc) => self.check_target(next_pc),
+            InstCode::Intersect(slot, next_pc) |
+            InstCode::Subtract(slot, next_pc) => {
+                self.check_slot(slot).and_then(|_| self.check_target(next_pc))
+            }
+            InstCode::Test(ref pred) => self.check_pred(pred, index_data),
+            InstCode::Match | InstCode::Next | InstCode::Noop | InstCode::AssertStart |
+            InstCode::AssertEnd => Ok(()),
+        }
+    }
+
+    /// Finds a `Save` of the end of a group that can run before the start of the group was
+    /// saved, which would leave the group ending before it starts. A start saved after the end
+    /// in a later iteration of a loop is not detected.
+    fn group_ending_before_start(&self) -> Option<(usize, &Group)> {
+        let len = self.inst_seq.len();
+        // The slots saved on every path to each instruction, narrowed until nothing changes
+        let mut saved: Vec<Option<Vec<bool>>> = vec![None; len];
+        let mut stack = vec![(0, vec![false; self.slots])];
+        while let Some((pc, mut state)) = stack.pop() {
+            if pc >= len {
+                continue;
+            }
+            if let Some(ref known) = saved[pc] {
+                let narrowed: Vec<bool> = known.iter().zip(&state).map(|(&k, &s)| k && s).collect();
+                if narrowed == *known {
+                    continue;
+                }
+                state = narrowed;
+            }
+            saved[pc] = Some(state.clone());
+            match self.inst_seq[pc] {
+                InstCode::Match => {}
+                InstCode::Jump(x) => stack.push((x, state)),
+                InstCode::Split(x, y) => {
+                    stack.push((x, state.clone()));
+                    stack.push((y, state));
+                }
+                InstCode::Look(_, next_pc) |
+                InstCode::Intersect(_, next_pc) |
+                InstCode::Subtract(_, next_pc) => {
+                    stack.push((pc + 1, state.clone()));
+                    stack.push((next_pc, state));
+                }
+                InstCode::Save(slot) => {
+                    state[slot] = true;
+                    stack.push((pc + 1, state));
+                }
+                _ => stack.push((pc + 1, state)),
+            }
+        }
+
+        self.inst_seq.iter().enumerate().filter_map(|(pc, inst)| match (inst, saved[pc].as_ref()) {
+            (&InstCode::Save(slot), Some(state)) => {
+                self.groups
+                    .iter()
+                    .find(|group| group.slot + 1 == slot && !state[group.slot])
+                    .map(|group| (pc, group))
+            }
+            _ => None,
+        }).next()
+    }
+
+    /// Follows the control flow from the start. The sub-programs of look-arounds and spans
+    /// are skipped, since their Match does not end the search.
+    fn reaches_match(&self) -> bool {
+        let len = self.inst_seq.len();
+        let mut reached = vec![false; len];
+        let mut stack = vec![0];
+        while let Some(pc) = stack.pop() {
+            if pc >= len || reached[pc] {
+                continue;
+            }
+            reached[pc] = true;
+            match self.inst_seq[pc] {
+                InstCode::Match => return true,
+                InstCode::Jump(x) => stack.push(x),
+                InstCode::Split(x, y) => {
+                    stack.push(x);
+                    stack.push(y);
+                }
+                InstCode::Look(_, next_pc) |
+                InstCode::Intersect(_, next_pc) |
+                InstCode::Subtract(_, next_pc) => stack.push(next_pc),
+                _ => stack.push(pc + 1),
+            }
+        }
+        false
+    }
+}
+
+/// How `VM` searches for matches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    /// Recursive backtracking, which supports every instruction.
+    Backtrack,
+    /// Thread lists in the `pike` module, for programs it supports.
+    Pike,
+    /// The lazy DFA in the `dfa` module, falling back to `Pike` for programs it does not
+    /// support and when its cache thrashes.
+    Dfa,
 }
 
 pub struct VM<'a> {
     inst_seq: &'a [InstCode],
+    program: &'a Program,
     input: BodyTable<'a>,
     index_data: &'a IndexData<'a>,
+    equalities: Equalities,
+    /// VMs running the programs of `Filter::Contains` and `Filter::NotContains`.
+    filter_vms: Vec<VM<'a>>,
+    /// Set when the Pike VM or the DFA was asked for and the Pike VM supports the program.
+    pike: Option<PikeVM<'a>>,
+    /// Set when the DFA was asked for and supports the program.
+    dfa: Option<LazyDFA<'a>>,
 }
 
 impl<'a> VM<'a> {
-    pub fn new(inst_seq: &'a [InstCode],
+    pub fn new(program: &'a Program,
                input: BodyTable<'a>,
-               index_data: &'a IndexData)
+               index_data: &'a IndexData,
+               engine: Engine)
                -> VM<'a> {
         VM {
-            inst_seq: inst_seq,
+            inst_seq: &program.inst_seq,
+            program: program,
             input: input,
             index_data: index_data,
+            equalities: VM::equalities(program, index_data),
+            filter_vms: program.filters
+                .iter()
+                .filter_map(|filter| match *filter {
+                    Filter::Contains(ref program) |
+                    Filter::NotContains(ref program) => {
+                        Some(VM::new(program, input, index_data, engine))
+                    }
+                    Filter::Length(..) => None,
+                })
+                .collect(),
+            pike: if engine != Engine::Backtrack && pike::supports(program) {
+                Some(PikeVM::new(program))
+            } else {
+                None
+            },
+            dfa: if engine == Engine::Dfa && dfa::supports(program) {
+                Some(LazyDFA::new(program))
+            } else {
+                None
+            },
         }
     }
 
-    pub fn parse(input: Vec<String>) -> Vec<InstCode> {
-        let mut inst_seq: Vec<InstCode> = vec![];
+    fn equalities(program: &Program, index_data: &IndexData) -> Equalities {
+        let mut pairs = vec![];
+        for inst in &program.inst_seq {
+            if let InstCode::Test(ref pred) = *inst {
+                pred.collect_equalities(&mut pairs);
+            }
+        }
 
-        for op_str in input {
-            let opcode_operand: Vec<&str> = op_str.split(":").collect();
-            let operands = &opcode_operand[1..];
-            inst_seq.push(match &opcode_operand[0][..] {
-                "Match" => InstCode::Match,
-                "Jump" => InstCode::Jump(operands[0].parse::<usize>().unwrap()),
-                "Expect" => {
-                    InstCode::Expect(operands[0].parse::<usize>().unwrap(),
-                                     operands[1].parse::<FeatId>().unwrap())
-                }
-                "Split" => {
-                    InstCode::Split(operands[0].parse::<usize>().unwrap(),
-                                    operands[1].parse::<usize>().unwrap())
-                }
-                "Next" => InstCode::Next,
-                "Noop" => InstCode::Noop,
-                _ => panic!("unsupported opcode"),
-            });
+        let mut equalities = Equalities::new();
+        for (left, right) in pairs {
+            if equalities.contains_key(&(left, right)) {
+                continue;
+            }
+            let ids: HashMap<&[u8], FeatId> = index_data.features_per_column[right]
+                .iter()
+                .enumerate()
+                .map(|(id, &feat)| (feat, id as FeatId))
+                .collect();
+            let table = index_data.features_per_column[left]
+                .iter()
+                .map(|feat| ids.get(feat).cloned())
+                .collect();
+            equalities.insert((left, right), table);
         }
+        equalities
+    }
+
+    fn accepts(&self, sentence: &BodyTable) -> bool {
+        let mut filter_vms = self.filter_vms.iter();
+        self.program.filters.iter().all(|filter| match *filter {
+            Filter::Contains(_) => filter_vms.next().unwrap().contains(sentence),
+            Filter::NotContains(_) => !filter_vms.next().unwrap().contains(sentence),
+            Filter::Length(min, max) => {
+                min <= sentence.len() && max.map_or(true, |max| sentence.len() <= max)
+            }
+        })
+    }
 
-        inst_seq
+    fn contains(&self, sentence: &BodyTable) -> bool {
+        (0..sentence.len()).any(|sp| {
+            let mut slots = vec![None; self.program.slots];
+            self.search(sentence, sp, &mut slots).is_some()
+        })
+    }
+
+    /// Finds the match with the highest priority that starts at `sp`.
+    fn search(&self,
+              sentence: &BodyTable,
+              sp: usize,
+              slots: &mut [Option<usize>])
+              -> Option<usize> {
+        if let Some(ref dfa) = self.dfa {
+            if let Ok(ret) = dfa.exec(sentence, sp, &self.equalities) {
+                return ret;
+            }
+        }
+        match self.pike {
+            Some(ref pike) => pike.exec(sentence, sp, &self.equalities, slots),
+            None => self.int_exec(sentence, 0, sp, None, slots),
+        }
     }
 
     pub fn exec(&self, writer: &mut io::Write, limit: Option<usize>) -> Option<()> {
@@ -61,10 +494,14 @@ impl<'a> VM<'a> {
 
         'outer: for &(begin, end) in self.index_data.sentence_index.iter() {
             let sentence = &self.input.slice(begin as usize, end as usize);
+            if !self.accepts(sentence) {
+                continue;
+            }
             let mut context: Option<Vec<&[u8]>> = None;
 
             for sp in 0..sentence.len() {
-                let ret = self.int_exec(sentence, 0, sp);
+                let mut slots = vec![None; self.program.slots];
+                let ret = self.search(sentence, sp, &mut slots);
                 if let Some(end_sp) = ret {
                     if context.is_none() {
                         let mut surface_list = Vec::<&[u8]>::with_capacity(sentence.len());
@@ -88,6 +525,23 @@ impl<'a> VM<'a> {
                     for &feat in &context[end_sp..] {
                         writer.write_all(feat).unwrap();
                     }
+                    for (i, group) in self.program.groups.iter().enumerate() {
+                        writer.write_all(b"\t").unwrap();
+                        match group.name {
+                            Some(ref name) => write!(writer, "{}:", name).unwrap(),
+                            None => write!(writer, "{}:", i + 1).unwrap(),
+                        }
+                        // A group whose end was saved before its start is left empty
+                        match (slots[group.slot], slots[group.slot + 1]) {
+                            (Some(begin), Some(end)) if begin <= end => {
+                                write!(writer, "{}-{}:", begin, end).unwrap();
+                                for &feat in &context[begin..end] {
+                                    writer.write_all(feat).unwrap();
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
                     writer.write_all(b"\n").unwrap();
 
                     result_size += 1;
@@ -103,39 +557,354 @@ impl<'a> VM<'a> {
         None
     }
 
-    fn int_exec(&self, sentence: &BodyTable, pc: usize, sp: usize) -> Option<usize> {
+    /// Runs the program from `pc` at `sp` and returns where the first match ends. With `end`,
+    /// only a match ending there counts.
+    pub fn int_exec(&self,
+                    sentence: &BodyTable,
+                    pc: usize,
+                    sp: usize,
+                    end: Option<usize>,
+                    slots: &mut [Option<usize>])
+                    -> Option<usize> {
         let mut pc = pc;
         let mut sp = sp;
 
-        while sp < sentence.len() && pc < self.inst_seq.len() {
+        while pc < self.inst_seq.len() {
             match self.inst_seq[pc] {
                 InstCode::Expect(col, feat) => {
-                    if sentence.columns[col][sp] == feat {
+                    if sp < sentence.len() && sentence.columns[col][sp] == feat {
+                        pc += 1;
+                    } else {
+                        return None;
+                    }
+                }
+                InstCode::ExpectNot(col, feat) => {
+                    if sp < sentence.len() && sentence.columns[col][sp] != feat {
+                        pc += 1;
+                    } else {
+                        return None;
+                    }
+                }
+                InstCode::ExpectIn(col, ref set) => {
+                    if sp < sentence.len() &&
+                       set.binary_search(&sentence.columns[col][sp]).is_ok() {
+                        pc += 1;
+                    } else {
+                        return None;
+                    }
+                }
+                InstCode::ExpectNotIn(col, ref set) => {
+                    if sp < sentence.len() &&
+                       set.binary_search(&sentence.columns[col][sp]).is_err() {
+                        pc += 1;
+                    } else {
+                        return None;
+                    }
+                }
+                InstCode::ExpectSame(col, slot) => {
+                    let column = sentence.columns[col];
+                    match slots[slot] {
+                        Some(bound) if sp < sentence.len() && column[sp] == column[bound] => {
+                            pc += 1;
+                        }
+                        _ => return None,
+                    }
+                }
+                InstCode::ExpectNotSame(col, slot) => {
+                    let column = sentence.columns[col];
+                    match slots[slot] {
+                        Some(bound) if sp < sentence.len() && column[sp] != column[bound] => {
+                            pc += 1;
+                        }
+                        _ => return None,
+                    }
+                }
+                InstCode::Test(ref pred) => {
+                    if sp < sentence.len() && pred.eval(sentence, sp, slots, &self.equalities) {
                         pc += 1;
                     } else {
                         return None;
                     }
                 }
                 InstCode::Next => {
-                    sp += 1;
-                    pc += 1;
+                    if sp < sentence.len() {
+                        sp += 1;
+                        pc += 1;
+                    } else {
+                        return None;
+                    }
                 }
                 InstCode::Jump(next_pc) => {
                     pc = next_pc;
                 }
                 InstCode::Split(x, y) => {
-                    return self.int_exec(sentence, x, sp)
-                        .or_else(|| self.int_exec(sentence, y, sp));
+                    if let Some(end_sp) = self.int_exec(sentence, x, sp, end, slots) {
+                        return Some(end_sp);
+                    }
+                    return self.int_exec(sentence, y, sp, end, slots);
                 }
                 InstCode::Match => {
+                    if end.map_or(false, |end| end != sp) {
+                        return None;
+                    }
                     return Some(sp);
                 }
                 InstCode::Noop => {
                     pc += 1;
                 }
+                InstCode::AssertStart => {
+                    if sp == 0 {
+                        pc += 1;
+                    } else {
+                        return None;
+                    }
+                }
+                InstCode::AssertEnd => {
+                    if sp == sentence.len() {
+                        pc += 1;
+                    } else {
+                        return None;
+                    }
+                }
+                InstCode::Progress(slot) => {
+                    if slots[slot] != Some(sp) {
+                        pc += 1;
+                    } else {
+                        return None;
+                    }
+                }
+                InstCode::Look(look, next_pc) => {
+                    // Captures made inside a look-around are discarded
+                    let mut look_slots = slots.to_vec();
+                    let found = if look.is_behind() {
+                        (0..sp + 1).any(|begin| {
+                            self.int_exec(sentence, pc + 1, begin, Some(sp), &mut look_slots)
+                                .is_some()
+                        })
+                    } else {
+                        self.int_exec(sentence, pc + 1, sp, None, &mut look_slots).is_some()
+                    };
+                    if found != look.is_negative() {
+                        pc = next_pc;
+                    } else {
+                        return None;
+                    }
+                }
+                InstCode::Intersect(slot, next_pc) |
+                InstCode::Subtract(slot, next_pc) => {
+                    let negative = match self.inst_seq[pc] {
+                        InstCode::Subtract(..) => true,
+                        _ => false,
+                    };
+                    // A span whose start was never saved cannot match
+                    let begin = match slots[slot] {
+                        Some(begin) => begin,
+                        None => return None,
+                    };
+                    let mut span_slots = slots.to_vec();
+                    let found = self.int_exec(sentence, pc + 1, begin, Some(sp), &mut span_slots)
+                        .is_some();
+                    if found != negative {
+                        pc = next_pc;
+                    } else {
+                        return None;
+                    }
+                }
+                InstCode::Save(slot) => {
+                    let saved = slots[slot];
+                    slots[slot] = Some(sp);
+                    if let Some(end_sp) = self.int_exec(sentence, pc + 1, sp, end, slots) {
+                        return Some(end_sp);
+                    }
+                    slots[slot] = saved;
+                    return None;
+                }
             };
         }
 
         return None;
     }
 }
+
+/// Fixtures shared by the tests of the compiler and the engines.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use compiler::{compile, parse};
+
+    /// Sentences of the first column, covering empty input, runs and alternating tokens.
+    pub const SENTENCES: &[&[FeatId]] = &[&[],
+                                         &[1],
+                                         &[1, 1],
+                                         &[1, 1, 1],
+                                         &[1, 1, 1, 1, 1],
+                                         &[1, 2, 1, 2],
+                                         &[2, 1, 1, 3],
+                                         &[1, 2, 1, 1, 2, 1, 2, 3],
+                                         &[1, 1, 2, 2, 1]];
+
+    pub fn compile_query(query: &str) -> Program {
+        compile(parse(query).unwrap().pattern)
+    }
+
+    /// A sentence whose first column is `tokens` and whose other columns are empty.
+    pub fn sentence(tokens: &[FeatId]) -> BodyTable {
+        let empty: &[FeatId] = &[];
+        let mut columns = [empty; COLS];
+        columns[0] = tokens;
+        BodyTable { columns: columns }
+    }
+
+    pub fn index_data(tokens: &[FeatId]) -> IndexData<'static> {
+        IndexData {
+            features_per_column: Default::default(),
+            sentence_index: vec![(0, tokens.len() as u32)],
+            schema: vec![],
+        }
+    }
+
+    /// Runs the program with backtracking from every position of `sentence(tokens)`, and
+    /// returns where each match ends along with its slots.
+    pub fn backtrack(program: &Program,
+                     tokens: &[FeatId])
+                     -> Vec<(Option<usize>, Vec<Option<usize>>)> {
+        let sentence = sentence(tokens);
+        let index_data = index_data(tokens);
+        let vm = VM::new(program, sentence, &index_data, Engine::Backtrack);
+        (0..tokens.len() + 1)
+            .map(|sp| {
+                let mut slots = vec![None; program.slots];
+                let end = vm.int_exec(&sentence, 0, sp, None, &mut slots);
+                (end, slots)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Engine, InstCode, Program, VM};
+    use super::testing::*;
+    use index::{BodyTable, FeatId, COLS};
+    use asm;
+
+    #[test]
+    fn span_without_saved_start_does_not_match() {
+        let source = ".slots 1\n\
+                      Intersect 0 done\n\
+                      Match\n\
+                      done:\n\
+                      Match";
+        let program = asm::assemble(source, &[]).unwrap();
+        assert!(program.validate(&index_data(&[1, 2])).is_ok());
+        assert!(backtrack(&program, &[1, 2]).iter().all(|&(end, _)| end.is_none()));
+    }
+
+    #[test]
+    fn group_ending_before_its_start_is_printed_empty() {
+        let source = ".group 0\n\
+                      Save 1\n\
+                      Next\n\
+                      Save 0\n\
+                      Match";
+        let program = asm::assemble(source, &[]).unwrap();
+        let tokens: &[FeatId] = &[0, 1];
+        let mut index_data = index_data(tokens);
+        for features in index_data.features_per_column.iter_mut() {
+            *features = vec![&b"a"[..], &b"b"[..]];
+        }
+        let body = BodyTable { columns: [tokens; COLS] };
+        let vm = VM::new(&program, body, &index_data, Engine::Backtrack);
+        let mut out = vec![];
+        vm.exec(&mut out, None);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 2);
+        assert!(out.lines().all(|line| line.ends_with("\t1:")), "{}", out);
+    }
+
+    fn validation_error(program: &Program) -> String {
+        match program.validate(&index_data(&[])) {
+            Ok(()) => panic!("{:?} is valid", program),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn validation_errors() {
+        let assemble = |source: &str| asm::assemble(source, &[]).unwrap();
+        assert_eq!(validation_error(&assemble("Jump 2\nMatch")),
+                   "invalid program at pc 0: target 2 is past the end of 2 instructions");
+        assert_eq!(validation_error(&assemble("Expect 0 0\nMatch")),
+                   "invalid program at pc 0: feature 0 is out of column 0, which has 0 features");
+        assert_eq!(validation_error(&assemble("Next\nJump 0\nMatch")),
+                   "invalid program: no Match is reachable from the start");
+        assert_eq!(validation_error(&assemble(".group 0\nSave 1\nNext\nSave 0\nMatch")),
+                   "invalid program at pc 0: group at slot 0 can end before it starts");
+
+        // The assembler rejects these, so the programs are broken after assembling them
+        let mut program = assemble("Save 1\nMatch");
+        program.slots = 1;
+        assert_eq!(validation_error(&program),
+                   "invalid program at pc 0: slot 1 is out of 1 slots");
+        let mut program = assemble("Expect 0 0\nMatch");
+        program.inst_seq[0] = InstCode::Expect(COLS, 0);
+        assert_eq!(validation_error(&program),
+                   format!("invalid program at pc 0: column {} does not exist", COLS));
+    }
+
+    #[test]
+    fn compiled_programs_are_valid() {
+        for query in &["(1)* (2 | (3))?",
+                       "(?<=(1) 2) (1+)",
+                       "(?:(1) []) & (?:[] (2))",
+                       "((1) 2)+ - 1",
+                       "(?:1 | (2)){2,3}"] {
+            let mut index_data = index_data(&[]);
+            index_data.features_per_column[0] = vec![&b""[..]; 4];
+            if let Err(err) = compile_query(query).validate(&index_data) {
+                panic!("`{}` is compiled into an invalid program: {}", query, err);
+            }
+        }
+    }
+
+    #[test]
+    fn filters_check_whole_sentences() {
+        let program = compile_query("1 within sentence where contains (3) and \
+                                     not contains (2 2) and length {2,4}");
+        let accepts = |tokens: &[FeatId]| {
+            let index_data = index_data(tokens);
+            let vm = VM::new(&program, sentence(tokens), &index_data, Engine::Backtrack);
+            vm.accepts(&sentence(tokens))
+        };
+        assert!(accepts(&[1, 3]));
+        assert!(accepts(&[2, 1, 2, 3]));
+        assert!(!accepts(&[1, 2]), "3 is missing");
+        assert!(!accepts(&[1, 2, 2, 3]), "2 2 is present");
+        assert!(!accepts(&[3]), "shorter than 2 tokens");
+        assert!(!accepts(&[1, 1, 1, 1, 3]), "longer than 4 tokens");
+    }
+
+    #[test]
+    fn equal_columns_compare_strings() {
+        let mut index_data = index_data(&[0, 1, 2]);
+        index_data.features_per_column[0] = vec!["走る".as_bytes(), "食べ".as_bytes(),
+                                                 "見る".as_bytes()];
+        index_data.features_per_column[1] = vec!["見る".as_bytes(), "走る".as_bytes(),
+                                                 "食べる".as_bytes()];
+        let mut body = sentence(&[0, 1, 2]);
+        body.columns[1] = &[1, 2, 0];
+        let starts = |query: &str| -> Vec<usize> {
+            let program = compile_query(query);
+            let vm = VM::new(&program, body, &index_data, Engine::Backtrack);
+            assert_eq!(vm.equalities[&(0, 1)], vec![Some(1), None, Some(0)]);
+            (0..3)
+                .filter(|&sp| {
+                    let mut slots = vec![None; program.slots];
+                    vm.int_exec(&body, 0, sp, None, &mut slots).is_some()
+                })
+                .collect()
+        };
+        assert_eq!(starts("[0==1]"), vec![0, 2]);
+        assert_eq!(starts("[0!=1]"), vec![1]);
+    }
+}
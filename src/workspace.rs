@@ -1,9 +1,28 @@
-use index::{self, IndexFileBundle};
+use asm;
+use compiler;
+use cqp;
+use dfa;
+use index::{self, IndexData, IndexFileBundle, Schema};
+use pike;
 use std::fs;
-use std::io;
+use std::io::{self, Read};
 use std::path::PathBuf;
 use std::time;
-use vm::{self, VM};
+use vm::{self, Engine, VM};
+
+#[derive(Clone, Copy)]
+pub enum Syntax {
+    Vecexp,
+    Cqp,
+}
+
+fn parse(query: &str, syntax: Syntax) -> io::Result<compiler::Query> {
+    let query = match syntax {
+        Syntax::Vecexp => compiler::parse(query),
+        Syntax::Cqp => cqp::parse(query),
+    };
+    query.map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))
+}
 
 pub struct Workspace {
     path: PathBuf,
@@ -14,33 +33,146 @@ impl Workspace {
         Workspace { path: path }
     }
 
-    pub fn create_index(&self, source_path: PathBuf) -> io::Result<()> {
+    pub fn create_index(&self, source_path: PathBuf, schema: Schema) -> io::Result<()> {
+        if schema.len() != index::COLS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("schema must have {} columns, but has {}",
+                                              index::COLS,
+                                              schema.len())));
+        }
+
         fs::create_dir(&self.path)?;
 
-        let indexer = index::Indexer::new(self);
+        let indexer = index::Indexer::new(self, schema);
 
         indexer.execute(source_path)?;
 
         Ok(())
     }
 
-    pub fn search2(&mut self, query: Vec<String>, limit: Option<usize>) -> io::Result<()> {
-        let iseq = VM::parse(query);
-        self.search(iseq, limit)
+    /// Runs a program written in the format of the `asm` module.
+    pub fn search2(&mut self,
+                   source: &str,
+                   engine: Engine,
+                   limit: Option<usize>)
+                   -> io::Result<()> {
+        let mut pools = vec![];
+        let index_data = self.index_data(&mut pools);
+        let program = asm::assemble(source, &index_data.schema)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        self.execute(&program, &index_data, engine, limit)
     }
 
-    pub fn search(&mut self, iseq: Vec<vm::InstCode>, limit: Option<usize>) -> io::Result<()> {
-        let mut bufs = vec![];
-        let body = self.body_table(&mut bufs);
+    pub fn query(&mut self,
+                 query: &str,
+                 syntax: Syntax,
+                 engine: Engine,
+                 limit: Option<usize>)
+                 -> io::Result<()> {
+        let mut pools = vec![];
+        let index_data = self.index_data(&mut pools);
+        let program = self.compile(query, syntax, &index_data)?;
+        self.execute(&program, &index_data, engine, limit)
+    }
 
+    /// Compiles a query and prints the program in the format of the `asm` module.
+    pub fn disassemble(&self, query: &str, syntax: Syntax) -> io::Result<String> {
         let mut pools = vec![];
         let index_data = self.index_data(&mut pools);
+        let program = self.compile(query, syntax, &index_data)?;
+        Ok(asm::disassemble(&program, &index_data))
+    }
+
+    fn compile(&self,
+               query: &str,
+               syntax: Syntax,
+               index_data: &IndexData)
+               -> io::Result<vm::Program> {
+        let query = parse(query, syntax)?;
+        let library = self.library()?;
+        let tree = compiler::expand(query, library)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+
+        let mut resolver = compiler::Resolver::new(&index_data.schema,
+                                                   &index_data.features_per_column);
+        let tree = resolver.resolve(tree)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        Ok(compiler::compile(compiler::optimize(tree)))
+    }
+
+    /// Prints a query as canonical text, optionally after expanding the definitions
+    /// and running the optimizer.
+    pub fn format(&self,
+                  query: &str,
+                  syntax: Syntax,
+                  expand: bool,
+                  optimize: bool)
+                  -> io::Result<String> {
+        let mut query = parse(query, syntax)?;
+        if expand {
+            let library = self.library()?;
+            let pattern = compiler::expand(query, library)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+            query = compiler::Query {
+                definitions: vec![],
+                pattern: pattern,
+            };
+        }
+        if optimize {
+            query = compiler::Query {
+                definitions: query.definitions
+                    .into_iter()
+                    .map(|(name, node)| (name, compiler::optimize(node)))
+                    .collect(),
+                pattern: compiler::optimize(query.pattern),
+            };
+        }
+        Ok(query.to_string())
+    }
+
+    /// Pattern definitions in `library.vq` are available to every query in the workspace.
+    fn library(&self) -> io::Result<Vec<(String, compiler::Node)>> {
+        let path = self.path.join("library.vq");
+        let mut file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        compiler::parse_definitions(&content).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("{}: {}", path.display(), err))
+        })
+    }
+
+    fn execute(&self,
+               program: &vm::Program,
+               index_data: &IndexData,
+               engine: Engine,
+               limit: Option<usize>)
+               -> io::Result<()> {
+        program.validate(index_data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+
+        let mut bufs = vec![];
+        let body = self.body_table(&mut bufs);
 
         let stdout = io::stdout();
         let handle = stdout.lock();
         let mut buffered = io::BufWriter::with_capacity(1024 * 1024, handle);
 
-        let vm = VM::new(iseq.as_slice(), body, &index_data);
+        match pike::unsupported(program) {
+            Some(reason) if engine != Engine::Backtrack => {
+                println_stderr!("the query has {}, falling back to backtracking.", reason);
+            }
+            _ if engine == Engine::Dfa && !dfa::supports(program) => {
+                println_stderr!("the query has groups or look-arounds, \
+                                 falling back to the Pike VM.");
+            }
+            _ => {}
+        }
+        let vm = VM::new(program, body, index_data, engine);
 
         println_stderr!("querying...");
         let now = time::Instant::now();
@@ -53,6 +185,18 @@ impl Workspace {
         Ok(())
     }
 
+    /// Accepts either a column name from the schema or a column number.
+    pub fn column(&self, column: &str) -> io::Result<usize> {
+        if let Ok(col) = column.parse::<usize>() {
+            return Ok(col);
+        }
+        let schema = self.schema_file().load()?;
+        schema.iter().position(|name| name == column).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           format!("column {} does not exist", column))
+        })
+    }
+
     pub fn lookup(&mut self, column: usize, pat: String) -> io::Result<Option<usize>> {
         let pat_bytes = pat.as_bytes();
         self.features_file(column).lookup(pat_bytes)
@@ -75,4 +219,8 @@ impl index::IndexFileBundle for Workspace {
     fn sentence_index_path(&self) -> PathBuf {
         self.path.join("sentence_index.bin")
     }
+
+    fn schema_path(&self) -> PathBuf {
+        self.path.join("schema.txt")
+    }
 }